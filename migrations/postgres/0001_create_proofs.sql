@@ -1,3 +1,5 @@
+-- baseline, the schema setup.sql used to create by hand, every statement is idempotent
+-- so that databases set up with setup.sql can adopt the migrations
 CREATE TABLE IF NOT EXISTS proofs ( 
     request_id UUID PRIMARY KEY,
     proof_type SMALLINT NOT NULL,
@@ -12,9 +14,21 @@ CREATE TABLE IF NOT EXISTS proofs (
     endpoint VARCHAR(128),
     public_inputs TEXT[],
     reason TEXT, 
-    identifier VARCHAR(255)
+    identifier VARCHAR(255),
+    instance_id VARCHAR(255),
+    error_class VARCHAR(32),
+    attempts SMALLINT NOT NULL DEFAULT 1,
+    encrypted_result BOOLEAN NOT NULL DEFAULT FALSE,
+    sealed_result BYTEA
 );
 
+ALTER TABLE proofs ADD COLUMN IF NOT EXISTS instance_id VARCHAR(255);
+ALTER TABLE proofs ADD COLUMN IF NOT EXISTS error_class VARCHAR(32);
+ALTER TABLE proofs ADD COLUMN IF NOT EXISTS attempts SMALLINT NOT NULL DEFAULT 1;
+ALTER TABLE proofs ADD COLUMN IF NOT EXISTS encrypted_result BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE proofs ADD COLUMN IF NOT EXISTS sealed_result BYTEA;
+CREATE INDEX IF NOT EXISTS proofs_instance_id_status_idx ON proofs (instance_id, status);
+
 CREATE OR REPLACE FUNCTION status_update_notify() RETURNS trigger AS $$
 DECLARE
   notification_payload JSON;
@@ -34,6 +48,9 @@ BEGIN
       'endpoint', NEW.endpoint,
       'public_inputs', NEW.public_inputs,
       'reason', NEW.reason,
+      'error_class', NEW.error_class,
+      'attempts', NEW.attempts,
+      'encrypted_result', NEW.encrypted_result,
       'identifier', NEW.identifier
     );
 
@@ -0,0 +1,18 @@
+-- queue of the requests that wait for or are held by a worker, only used with --job-queue database,
+-- a row is claimed by setting leased_by and kept by pushing lease_expires_at forward,
+-- rows whose lease expired can be claimed by any instance
+CREATE TABLE IF NOT EXISTS jobs (
+    request_id UUID PRIMARY KEY REFERENCES proofs (request_id) ON DELETE CASCADE,
+    proof_type SMALLINT NOT NULL,
+    circuit_name VARCHAR(255) NOT NULL,
+    -- circuit inputs and result key, sealed under the job queue key
+    sealed_inputs BYTEA NOT NULL,
+    sealed_result_key BYTEA,
+    enqueued_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    leased_by VARCHAR(255),
+    lease_expires_at TIMESTAMP WITH TIME ZONE,
+    heartbeat_at TIMESTAMP WITH TIME ZONE,
+    claims SMALLINT NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS jobs_lease_expires_at_idx ON jobs (lease_expires_at);
@@ -0,0 +1,3 @@
+-- set when the retention task scrubbed the payload of a finished request, see --retention-hours
+ALTER TABLE proofs ADD COLUMN IF NOT EXISTS purged_at TIMESTAMP WITH TIME ZONE;
+CREATE INDEX IF NOT EXISTS proofs_created_at_idx ON proofs (created_at);
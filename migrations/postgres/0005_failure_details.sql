@@ -0,0 +1,43 @@
+-- where and how a failed request failed, reason only holds a short summary,
+-- stderr is truncated so that the notification stays below the pg_notify limit
+ALTER TABLE proofs ADD COLUMN IF NOT EXISTS failed_stage VARCHAR(16);
+ALTER TABLE proofs ADD COLUMN IF NOT EXISTS exit_code INTEGER;
+ALTER TABLE proofs ADD COLUMN IF NOT EXISTS stderr TEXT;
+
+CREATE OR REPLACE FUNCTION status_update_notify() RETURNS trigger AS $$
+DECLARE
+  notification_payload JSON;
+BEGIN
+  IF (TG_OP = 'UPDATE' AND NEW.status IS DISTINCT FROM OLD.status) OR TG_OP = 'INSERT' THEN
+    notification_payload = json_build_object(
+      'request_id', NEW.request_id,
+      'proof_type', NEW.proof_type,
+      'status', NEW.status,
+      'created_at', NEW.created_at,
+      'circuit_name', NEW.circuit_name,
+      'onchain', NEW.onchain,
+      'witness_generated_at', NEW.witness_generated_at,
+      'proof_generated_at', NEW.proof_generated_at,
+      'proof', NEW.proof,
+      'endpoint_type', NEW.endpoint_type,
+      'endpoint', NEW.endpoint,
+      'public_inputs', NEW.public_inputs,
+      'reason', NEW.reason,
+      'error_class', NEW.error_class,
+      'failed_stage', NEW.failed_stage,
+      'exit_code', NEW.exit_code,
+      'stderr', NEW.stderr,
+      'attempts', NEW.attempts,
+      'encrypted_result', NEW.encrypted_result,
+      'identifier', NEW.identifier,
+      'version', NEW.version,
+      'user_defined_data', NEW.user_defined_data,
+      'self_defined_data', NEW.self_defined_data
+    );
+
+    PERFORM pg_notify('status_update', notification_payload::text);
+  END IF;
+
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
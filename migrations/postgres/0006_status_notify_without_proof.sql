@@ -0,0 +1,38 @@
+-- pg_notify fails above 8000 bytes and takes the INSERT or UPDATE down with it,
+-- proof and public_inputs are the only columns that can grow that large so they
+-- are left out, the listeners read them from the row
+CREATE OR REPLACE FUNCTION status_update_notify() RETURNS trigger AS $$
+DECLARE
+  notification_payload JSON;
+BEGIN
+  IF (TG_OP = 'UPDATE' AND NEW.status IS DISTINCT FROM OLD.status) OR TG_OP = 'INSERT' THEN
+    notification_payload = json_build_object(
+      'request_id', NEW.request_id,
+      'proof_type', NEW.proof_type,
+      'status', NEW.status,
+      'created_at', NEW.created_at,
+      'circuit_name', NEW.circuit_name,
+      'onchain', NEW.onchain,
+      'witness_generated_at', NEW.witness_generated_at,
+      'proof_generated_at', NEW.proof_generated_at,
+      'endpoint_type', NEW.endpoint_type,
+      'endpoint', NEW.endpoint,
+      'reason', NEW.reason,
+      'error_class', NEW.error_class,
+      'failed_stage', NEW.failed_stage,
+      'exit_code', NEW.exit_code,
+      'stderr', NEW.stderr,
+      'attempts', NEW.attempts,
+      'encrypted_result', NEW.encrypted_result,
+      'identifier', NEW.identifier,
+      'version', NEW.version,
+      'user_defined_data', NEW.user_defined_data,
+      'self_defined_data', NEW.self_defined_data
+    );
+
+    PERFORM pg_notify('status_update', notification_payload::text);
+  END IF;
+
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
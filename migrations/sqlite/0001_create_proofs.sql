@@ -0,0 +1,27 @@
+-- same columns as the postgres schema, proof and public_inputs hold JSON text
+CREATE TABLE IF NOT EXISTS proofs (
+    request_id BLOB PRIMARY KEY,
+    proof_type INTEGER NOT NULL,
+    status INTEGER NOT NULL DEFAULT 0,
+    circuit_name TEXT NOT NULL,
+    onchain BOOLEAN NOT NULL,
+    created_at TEXT,
+    witness_generated_at TEXT,
+    proof_generated_at TEXT,
+    proof TEXT,
+    endpoint_type TEXT,
+    endpoint TEXT,
+    public_inputs TEXT,
+    reason TEXT,
+    identifier TEXT,
+    version INTEGER,
+    user_defined_data TEXT,
+    self_defined_data TEXT,
+    instance_id TEXT,
+    error_class TEXT,
+    attempts INTEGER NOT NULL DEFAULT 1,
+    encrypted_result BOOLEAN NOT NULL DEFAULT FALSE,
+    sealed_result BLOB
+);
+
+CREATE INDEX IF NOT EXISTS proofs_instance_id_status_idx ON proofs (instance_id, status);
@@ -0,0 +1,3 @@
+-- set when the retention task scrubbed the payload of a finished request, see --retention-hours
+ALTER TABLE proofs ADD COLUMN purged_at TEXT;
+CREATE INDEX IF NOT EXISTS proofs_created_at_idx ON proofs (created_at);
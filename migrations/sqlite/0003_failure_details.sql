@@ -0,0 +1,4 @@
+-- where and how a failed request failed, reason only holds a short summary
+ALTER TABLE proofs ADD COLUMN failed_stage TEXT;
+ALTER TABLE proofs ADD COLUMN exit_code INTEGER;
+ALTER TABLE proofs ADD COLUMN stderr TEXT;
@@ -0,0 +1,8 @@
+-- set when a request reaches a terminal status, the retention policy counts from it
+-- so that a request is never purged right after it finished
+ALTER TABLE proofs ADD COLUMN completed_at TEXT;
+CREATE INDEX IF NOT EXISTS proofs_completed_at_idx ON proofs (completed_at);
+
+-- requests that finished before the column existed, proof_generated, failed and cancelled
+UPDATE proofs SET completed_at = COALESCE(proof_generated_at, created_at)
+WHERE completed_at IS NULL AND status IN (2, 3, 4);
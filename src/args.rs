@@ -1,19 +1,59 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::attestation::AttestationProviderKind;
+use crate::generator::parse_circuit_timeout;
+use crate::job_queue::JobQueueKind;
+use crate::rate_limit::{Limit, RateLimiter};
+use crate::retention::RetentionMode;
+use crate::retry::RetryPolicy;
+use crate::scheduler::parse_weight;
+use crate::worker_pool::{default_memory_budget_mb, MemoryBudget};
+
+#[derive(Subcommand, Debug, Clone, Copy)]
+pub enum Command {
+    /// Apply the pending database migrations and exit
+    Migrate,
+}
 
 #[derive(Parser, Debug)]
 pub struct Config {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Web server bind address (e.g., 0.0.0.0:3001)
     #[arg(short, long, default_value = "0.0.0.0:3001")]
     pub server_address: String,
 
+    /// Bind address of the operator methods (queue_depth, rate_limits, retention), keep it off the public network
+    #[arg(long, default_value = "127.0.0.1:3002")]
+    pub admin_address: String,
+
     /// Secret manager project id
-    #[arg(short, long)]
-    pub project_id: String,
+    #[arg(short = 'd', long, env = "PROJECT_ID")]
+    pub project_id: Option<String>,
 
     /// Secret manager secret id
-    #[arg(short, long, default_value = "DB_URL")]
+    #[arg(long, env = "SECRET_ID", default_value = "DB_URL")]
     pub secret_id: String,
 
+    /// Database url (postgres://, sqlite: or memory://), used instead of the secret manager
+    #[arg(long, env = "DATABASE_URL", hide_env_values = true)]
+    pub database_url: Option<String>,
+
+    /// File containing the database url, used instead of the secret manager
+    #[arg(long)]
+    pub database_url_file: Option<PathBuf>,
+
+    /// Apply the pending database migrations at startup instead of only checking that there are none
+    #[arg(long)]
+    pub migrate: bool,
+
+    /// Local development mode: no secret manager, mock attestation by default
+    #[arg(long)]
+    pub dev: bool,
+
     /// Circuit folder path
     #[arg(short = 'c', long, default_value = "../circuits")]
     pub circuit_folder: String,
@@ -25,4 +65,219 @@ pub struct Config {
     /// Rapidsnark path
     #[arg(short = 'r', long, default_value = "./rapidsnark")]
     pub rapidsnark_path: String,
+
+    /// Identifier of this server in the proofs table, used to recover its own jobs after a restart, must stay the same across restarts [default: hostname with --dev, required otherwise]
+    #[arg(long, env = "INSTANCE_ID")]
+    pub instance_id: Option<String>,
+
+    /// Number of witness generations that may run at once [default: number of CPUs]
+    #[arg(long)]
+    pub witness_workers: Option<usize>,
+
+    /// Number of proof generations that may run at once
+    #[arg(long, default_value_t = 1)]
+    pub prover_workers: usize,
+
+    /// Memory in MB that running jobs may claim, 0 disables the check [default: 80% of the total memory]
+    #[arg(long)]
+    pub memory_budget_mb: Option<u32>,
+
+    /// Scheduling weight of a proof type, e.g. disclose=8 (repeatable) [default: disclose 4, dsc 2, register 1]
+    #[arg(long, value_parser = parse_weight)]
+    pub priority_weight: Vec<(String, u32)>,
+
+    /// Seconds a queued job waits before its weight is raised by one, 0 disables aging
+    #[arg(long, default_value_t = 30)]
+    pub priority_aging_secs: u64,
+
+    /// Seconds a witness generation may run before it is killed
+    #[arg(long, default_value_t = 600)]
+    pub witness_timeout_secs: u64,
+
+    /// Seconds a proof generation may run before it is killed
+    #[arg(long, default_value_t = 600)]
+    pub proof_timeout_secs: u64,
+
+    /// Witness timeout of a single circuit, e.g. register_sha256_sha256_sha256_rsa_65537_4096=1200 (repeatable)
+    #[arg(long, value_parser = parse_circuit_timeout)]
+    pub circuit_witness_timeout: Vec<(String, u64)>,
+
+    /// Proof timeout of a single circuit, e.g. register_sha256_sha256_sha256_rsa_65537_4096=1200 (repeatable)
+    #[arg(long, value_parser = parse_circuit_timeout)]
+    pub circuit_proof_timeout: Vec<(String, u64)>,
+
+    /// Accepted requests that may be unfinished at once, further submissions are rejected with QueueFull
+    #[arg(long, default_value_t = 100)]
+    pub max_pending_jobs: usize,
+
+    /// Seconds a client rejected by a full queue is told to wait before retrying
+    #[arg(long, default_value_t = 10)]
+    pub queue_full_retry_after_secs: u64,
+
+    /// Number of hello sessions kept at once, the oldest is dropped when full
+    #[arg(long, default_value_t = 1000)]
+    pub session_capacity: usize,
+
+    /// Seconds a hello session stays valid for submit_request
+    #[arg(long, default_value_t = 300)]
+    pub session_ttl_secs: u64,
+
+    /// Seconds between rotations of the submit_sealed HPKE key, the previous key stays valid for one more period
+    #[arg(long, default_value_t = 3600)]
+    pub hpke_rotation_secs: u64,
+
+    /// Requests a client may send per minute over all methods, 0 disables the limit
+    #[arg(long, default_value_t = 600)]
+    pub rate_limit_per_minute: u32,
+
+    /// Requests a client may send at once before rate_limit_per_minute applies
+    #[arg(long, default_value_t = 60)]
+    pub rate_limit_burst: u32,
+
+    /// Calls a client may make per minute to each of hello, submit_request, submit_sealed and attestation, 0 disables the limit
+    #[arg(long, default_value_t = 20)]
+    pub expensive_rate_limit_per_minute: u32,
+
+    /// Calls a client may make at once to each expensive method before expensive_rate_limit_per_minute applies
+    #[arg(long, default_value_t = 5)]
+    pub expensive_rate_limit_burst: u32,
+
+    /// Number of clients whose rate limits are tracked at once, the least recently seen is dropped when full
+    #[arg(long, default_value_t = 100_000)]
+    pub rate_limit_clients: usize,
+
+    /// Account requests to the last X-Forwarded-For address, only set behind a proxy that appends it
+    #[arg(long)]
+    pub trust_forwarded_for: bool,
+
+    /// Attempts of a pipeline step before a transient failure fails the request
+    #[arg(long, default_value_t = 3)]
+    pub max_attempts: u32,
+
+    /// Delay in milliseconds before the first retry, doubled on every further retry
+    #[arg(long, default_value_t = 500)]
+    pub retry_base_delay_ms: u64,
+
+    /// Upper bound in milliseconds of the delay between retries
+    #[arg(long, default_value_t = 30_000)]
+    pub retry_max_delay_ms: u64,
+
+    /// Where accepted jobs wait for a worker, database lets every instance sharing the Postgres database work on them
+    #[arg(long, value_enum, default_value_t = JobQueueKind::Local)]
+    pub job_queue: JobQueueKind,
+
+    /// Base64 encoded 32 byte key that seals the circuit inputs in the database job queue, the same on every instance
+    #[arg(long, env = "JOB_QUEUE_KEY", hide_env_values = true)]
+    pub job_queue_key: Option<String>,
+
+    /// Seconds a claimed job stays leased without a heartbeat before another instance may claim it
+    #[arg(long, default_value_t = 60)]
+    pub job_lease_secs: u64,
+
+    /// Jobs of the database job queue this instance works on at once [default: witness workers + prover workers]
+    #[arg(long)]
+    pub job_queue_capacity: Option<usize>,
+
+    /// Milliseconds between polls of the database job queue while it has nothing to claim
+    #[arg(long, default_value_t = 1000)]
+    pub job_queue_poll_ms: u64,
+
+    /// Times a job may be claimed before it is failed, so that a job that brings down every instance is given up
+    #[arg(long, default_value_t = 3)]
+    pub job_max_claims: u32,
+
+    /// Hours after which the payload of a finished request is purged, 0 keeps every request forever
+    #[arg(long, default_value_t = 0)]
+    pub retention_hours: u64,
+
+    /// What a purge does to a finished request past retention_hours
+    #[arg(long, value_enum, default_value_t = RetentionMode::Scrub)]
+    pub retention_mode: RetentionMode,
+
+    /// Seconds between two purges of the finished requests past retention_hours
+    #[arg(long, default_value_t = 3600)]
+    pub retention_interval_secs: u64,
+
+    /// Attestation backend of the TEE the server runs in [default: confidential-space, mock with --dev]
+    #[arg(long, value_enum)]
+    pub attestation_provider: Option<AttestationProviderKind>,
+}
+
+impl Config {
+    pub fn attestation_provider_kind(&self) -> Result<AttestationProviderKind, String> {
+        match self.attestation_provider {
+            //the mock attestation proves nothing, never serve it outside development
+            Some(AttestationProviderKind::Mock) if !self.dev => {
+                Err("--attestation-provider mock requires --dev".to_string())
+            }
+            Some(kind) => Ok(kind),
+            None if self.dev => Ok(AttestationProviderKind::Mock),
+            None => Ok(AttestationProviderKind::ConfidentialSpace),
+        }
+    }
+
+    pub fn instance_id(&self) -> Result<String, String> {
+        if let Some(instance_id) = &self.instance_id {
+            return Ok(instance_id.clone());
+        }
+        //the hostname of a container or VM changes on restart, and the requests of the
+        //previous one would never be recovered
+        if !self.dev {
+            return Err("--instance-id is required outside of --dev".to_string());
+        }
+        match std::fs::read_to_string("/proc/sys/kernel/hostname") {
+            Ok(hostname) => Ok(hostname.trim().to_string()),
+            Err(_) => Ok("tee-server".to_string()),
+        }
+    }
+
+    pub fn witness_workers(&self) -> usize {
+        match self.witness_workers {
+            Some(workers) => workers.max(1),
+            None => std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+        }
+    }
+
+    pub fn job_queue_capacity(&self) -> usize {
+        match self.job_queue_capacity {
+            Some(capacity) => capacity.max(1),
+            None => self.witness_workers() + self.prover_workers.max(1),
+        }
+    }
+
+    pub fn retention_max_age(&self) -> Option<std::time::Duration> {
+        match self.retention_hours {
+            0 => None,
+            hours => Some(std::time::Duration::from_secs(hours.saturating_mul(3600))),
+        }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::new(
+            self.max_attempts,
+            std::time::Duration::from_millis(self.retry_base_delay_ms),
+            std::time::Duration::from_millis(self.retry_max_delay_ms),
+        )
+    }
+
+    pub fn rate_limiter(&self) -> RateLimiter {
+        RateLimiter::new(
+            Limit::per_minute(self.rate_limit_per_minute, self.rate_limit_burst),
+            Limit::per_minute(
+                self.expensive_rate_limit_per_minute,
+                self.expensive_rate_limit_burst,
+            ),
+            self.rate_limit_clients,
+        )
+    }
+
+    pub fn memory_budget(&self) -> Option<MemoryBudget> {
+        match self.memory_budget_mb {
+            Some(0) => None,
+            Some(total_mb) => Some(MemoryBudget::new(total_mb)),
+            None => default_memory_budget_mb().map(MemoryBudget::new),
+        }
+    }
 }
@@ -0,0 +1,77 @@
+use std::error::Error;
+
+use base64::engine::{general_purpose, Engine};
+use hyper::body::Buf;
+use hyper::{Body, Client, Request};
+use hyperlocal::{UnixClientExt, Uri as HyperlocalUri};
+use jsonrpsee::core::async_trait;
+use serde::Serialize;
+
+use super::{validate_lengths, AttestationProvider, AttestationRequest};
+
+const TEE_SERVER_SOCKET: &str = "/run/container_launcher/teeserver.sock";
+
+// Confidential Space accepts nonces of 10 to 74 bytes; nonces are sent base64 encoded,
+// which leaves 7 to 54 bytes of raw data
+pub const MIN_NONCE_BYTES: usize = 7;
+pub const MAX_NONCE_BYTES: usize = 54;
+
+#[derive(Serialize)]
+struct TokenRequest<'a> {
+    audience: &'a str,
+    token_type: &'a str,
+    nonces: Vec<&'a str>,
+}
+
+pub struct ConfidentialSpace {
+    socket_path: String,
+}
+
+impl Default for ConfidentialSpace {
+    fn default() -> Self {
+        Self {
+            socket_path: TEE_SERVER_SOCKET.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl AttestationProvider for ConfidentialSpace {
+    fn validate(&self, request: &AttestationRequest) -> Result<(), String> {
+        validate_lengths(request, MIN_NONCE_BYTES, MAX_NONCE_BYTES)
+    }
+
+    async fn attest(
+        &self,
+        request: AttestationRequest,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let nonces: Vec<String> = request
+            .fields()
+            .into_iter()
+            .filter_map(|(_, value)| value.map(|value| general_purpose::STANDARD.encode(value)))
+            .collect();
+
+        let request_body = TokenRequest {
+            audience: "USER",
+            token_type: "PKI",
+            nonces: nonces.iter().map(|nonce| nonce.as_str()).collect(),
+        };
+        let json = serde_json::to_string(&request_body)?;
+
+        let client = Client::unix();
+
+        // Fix: explicit type for URI
+        let uri: hyper::Uri = HyperlocalUri::new(&self.socket_path, "/v1/token").into();
+
+        let req = Request::post(uri)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json))?;
+
+        let res = client.request(req).await?;
+        let mut bytes = hyper::body::aggregate(res).await?;
+        let token_bytes = bytes.copy_to_bytes(bytes.remaining()).to_vec();
+
+        println!("Token Response: {}", String::from_utf8_lossy(&token_bytes));
+        Ok(token_bytes)
+    }
+}
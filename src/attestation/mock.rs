@@ -0,0 +1,43 @@
+use std::error::Error;
+
+use base64::engine::{general_purpose, Engine};
+use jsonrpsee::core::async_trait;
+use serde::Serialize;
+
+use super::{validate_lengths, AttestationProvider, AttestationRequest};
+
+const MAX_FIELD_BYTES: usize = 1024;
+
+#[derive(Serialize)]
+struct MockDocument {
+    provider: &'static str,
+    user_data: Option<String>,
+    nonce: Option<String>,
+    public_key: Option<String>,
+}
+
+//not an attestation at all, the same request always yields the same document
+pub struct Mock;
+
+#[async_trait]
+impl AttestationProvider for Mock {
+    fn validate(&self, request: &AttestationRequest) -> Result<(), String> {
+        validate_lengths(request, 0, MAX_FIELD_BYTES)
+    }
+
+    async fn attest(
+        &self,
+        request: AttestationRequest,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let encode =
+            |value: Option<Vec<u8>>| value.map(|value| general_purpose::STANDARD.encode(value));
+
+        let document = MockDocument {
+            provider: "mock",
+            user_data: encode(request.user_data),
+            nonce: encode(request.nonce),
+            public_key: encode(request.public_key),
+        };
+        Ok(serde_json::to_vec(&document)?)
+    }
+}
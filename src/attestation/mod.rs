@@ -0,0 +1,78 @@
+pub mod confidential_space;
+pub mod mock;
+pub mod nitro;
+
+use std::error::Error;
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use jsonrpsee::core::async_trait;
+
+#[derive(Debug, Default, Clone)]
+pub struct AttestationRequest {
+    pub user_data: Option<Vec<u8>>,
+    pub nonce: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl AttestationRequest {
+    //fields in the order they are bound into the attestation
+    pub fn fields(&self) -> [(&'static str, Option<&Vec<u8>>); 3] {
+        [
+            ("user_data", self.user_data.as_ref()),
+            ("nonce", self.nonce.as_ref()),
+            ("public_key", self.public_key.as_ref()),
+        ]
+    }
+}
+
+#[async_trait]
+pub trait AttestationProvider: Send + Sync {
+    //checks that the request fits the limits of the backend
+    fn validate(&self, request: &AttestationRequest) -> Result<(), String>;
+
+    async fn attest(
+        &self,
+        request: AttestationRequest,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>>;
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum AttestationProviderKind {
+    /// Google Cloud Confidential Space launcher token
+    ConfidentialSpace,
+    /// AWS Nitro Enclaves NSM attestation document
+    Nitro,
+    /// Deterministic fake attestation for local development
+    Mock,
+}
+
+impl AttestationProviderKind {
+    pub fn build(self) -> Arc<dyn AttestationProvider> {
+        match self {
+            AttestationProviderKind::ConfidentialSpace => {
+                Arc::new(confidential_space::ConfidentialSpace::default())
+            }
+            AttestationProviderKind::Nitro => Arc::new(nitro::Nitro::default()),
+            AttestationProviderKind::Mock => Arc::new(mock::Mock),
+        }
+    }
+}
+
+pub(crate) fn validate_lengths(
+    request: &AttestationRequest,
+    min_len: usize,
+    max_len: usize,
+) -> Result<(), String> {
+    for (name, value) in request.fields() {
+        if let Some(value) = value {
+            if value.len() < min_len || value.len() > max_len {
+                return Err(format!(
+                    "{} must be between {} and {} bytes",
+                    name, min_len, max_len
+                ));
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::os::fd::AsRawFd;
+
+use jsonrpsee::core::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use super::{AttestationProvider, AttestationRequest};
+
+const NSM_DEVICE: &str = "/dev/nsm";
+
+const NSM_REQUEST_MAX_SIZE: usize = 0x1000;
+const NSM_RESPONSE_MAX_SIZE: usize = 0x3000;
+
+// _IOWR(0x0A, 0, struct nsm_message)
+const NSM_IOCTL_REQUEST: u64 = 0xC020_0A00;
+
+const MAX_USER_DATA_BYTES: usize = 512;
+const MAX_NONCE_BYTES: usize = 512;
+const MAX_PUBLIC_KEY_BYTES: usize = 1024;
+
+#[repr(C)]
+struct NsmMessage {
+    request: libc::iovec,
+    response: libc::iovec,
+}
+
+#[derive(Serialize)]
+enum NsmRequest {
+    Attestation {
+        user_data: Option<ByteBuf>,
+        nonce: Option<ByteBuf>,
+        public_key: Option<ByteBuf>,
+    },
+}
+
+#[derive(Deserialize)]
+enum NsmResponse {
+    Attestation { document: ByteBuf },
+    Error(String),
+}
+
+//returns the COSE_Sign1 attestation document produced by the Nitro Secure Module
+pub struct Nitro {
+    device_path: String,
+}
+
+impl Default for Nitro {
+    fn default() -> Self {
+        Self {
+            device_path: NSM_DEVICE.to_string(),
+        }
+    }
+}
+
+impl Nitro {
+    fn process_request(device_path: &str, request: &[u8]) -> Result<Vec<u8>, String> {
+        if request.len() > NSM_REQUEST_MAX_SIZE {
+            return Err("NSM request is too large".to_string());
+        }
+
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(device_path)
+            .map_err(|e| format!("Could not open {}: {}", device_path, e))?;
+
+        let mut response = vec![0u8; NSM_RESPONSE_MAX_SIZE];
+        let mut message = NsmMessage {
+            request: libc::iovec {
+                iov_base: request.as_ptr() as *mut libc::c_void,
+                iov_len: request.len(),
+            },
+            response: libc::iovec {
+                iov_base: response.as_mut_ptr() as *mut libc::c_void,
+                iov_len: response.len(),
+            },
+        };
+
+        // SAFETY: both iovecs point to live buffers of the given length and the driver
+        // only writes up to response.iov_len bytes into the response buffer
+        let result = unsafe {
+            libc::ioctl(
+                device.as_raw_fd(),
+                NSM_IOCTL_REQUEST as _,
+                &mut message as *mut NsmMessage,
+            )
+        };
+        if result < 0 {
+            return Err(format!(
+                "NSM ioctl failed: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        response.truncate(message.response.iov_len);
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl AttestationProvider for Nitro {
+    fn validate(&self, request: &AttestationRequest) -> Result<(), String> {
+        for ((name, value), max_len) in request.fields().into_iter().zip([
+            MAX_USER_DATA_BYTES,
+            MAX_NONCE_BYTES,
+            MAX_PUBLIC_KEY_BYTES,
+        ]) {
+            if let Some(value) = value {
+                if value.len() > max_len {
+                    return Err(format!("{} must be at most {} bytes", name, max_len));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn attest(
+        &self,
+        request: AttestationRequest,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let nsm_request = serde_cbor::to_vec(&NsmRequest::Attestation {
+            user_data: request.user_data.map(ByteBuf::from),
+            nonce: request.nonce.map(ByteBuf::from),
+            public_key: request.public_key.map(ByteBuf::from),
+        })?;
+
+        let device_path = self.device_path.clone();
+        let nsm_response =
+            tokio::task::spawn_blocking(move || Nitro::process_request(&device_path, &nsm_request))
+                .await??;
+
+        match serde_cbor::from_slice::<NsmResponse>(&nsm_response)? {
+            NsmResponse::Attestation { document } => Ok(document.into_vec()),
+            NsmResponse::Error(e) => Err(format!("NSM returned an error: {}", e).into()),
+        }
+    }
+}
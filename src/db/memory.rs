@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use jsonrpsee::core::async_trait;
+use sqlx::types::chrono::{DateTime, Utc};
+use tokio::sync::broadcast;
+
+use super::types::{ProofStatus, SealedResult, Status, StatusUpdate, UnfinishedProof};
+use super::{forward_status_updates, Failure, NewProof, ProofOutput, ProofStore, CANCELLED_REASON};
+use crate::retention::RetentionMode;
+
+struct Record {
+    proof_status: ProofStatus,
+    instance_id: String,
+    sealed_result: Option<Vec<u8>>,
+    //when the request reached a terminal status, the retention policy counts from it
+    completed_at: Option<DateTime<Utc>>,
+}
+
+//requests kept in memory only, for tests and local development, lost on restart
+pub struct MemoryStore {
+    records: Mutex<HashMap<uuid::Uuid, Record>>,
+    status_updates: broadcast::Sender<ProofStatus>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        let (status_updates, _) = broadcast::channel(1024);
+        Self {
+            records: Mutex::new(HashMap::new()),
+            status_updates,
+        }
+    }
+
+    //applies update to a request that is still in the pipeline and sends its new status
+    fn update(&self, uuid: uuid::Uuid, update: impl FnOnce(&mut Record)) {
+        let mut records = self.records.lock().unwrap();
+        if let Some(record) = records.get_mut(&uuid) {
+            if !record.proof_status.status.is_terminal() {
+                update(record);
+                if record.proof_status.status.is_terminal() {
+                    record.completed_at = Some(Utc::now());
+                }
+                let _ = self.status_updates.send(record.proof_status.clone());
+            }
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl ProofStore for MemoryStore {
+    async fn migrate(&self) -> Result<usize, String> {
+        Ok(0)
+    }
+
+    async fn check_schema(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn create_proof_status(&self, proof: NewProof<'_>) -> Result<bool, String> {
+        let mut records = self.records.lock().unwrap();
+        if records.contains_key(&proof.uuid) {
+            return Ok(false);
+        }
+        let proof_status = ProofStatus {
+            request_id: proof.uuid,
+            status: Status::Pending,
+            proof_type: proof.proof_type.clone(),
+            circuit_name: proof.circuit_name.to_string(),
+            created_at: Some(Utc::now()),
+            witness_generated_at: None,
+            proof_generated_at: None,
+            reason: None,
+            error_class: None,
+            failed_stage: None,
+            exit_code: None,
+            stderr: None,
+            attempts: 1,
+            encrypted_result: proof.encrypt_result,
+            proof: None,
+            public_inputs: None,
+            purged_at: None,
+        };
+        let _ = self.status_updates.send(proof_status.clone());
+        records.insert(
+            proof.uuid,
+            Record {
+                proof_status,
+                instance_id: proof.instance_id.to_string(),
+                sealed_result: None,
+                completed_at: None,
+            },
+        );
+        Ok(true)
+    }
+
+    async fn set_witness_generated(&self, uuid: uuid::Uuid) -> Result<(), sqlx::Error> {
+        self.update(uuid, |record| {
+            record.proof_status.status = Status::WitnessGenerated;
+            record.proof_status.witness_generated_at = Some(Utc::now());
+        });
+        Ok(())
+    }
+
+    async fn update_proof(
+        &self,
+        uuid: uuid::Uuid,
+        output: &ProofOutput,
+    ) -> Result<(), sqlx::Error> {
+        self.update(uuid, |record| {
+            record.proof_status.status = Status::ProofGenererated;
+            record.proof_status.proof_generated_at = Some(Utc::now());
+            match output {
+                ProofOutput::Sealed(sealed_result) => {
+                    record.sealed_result = Some(sealed_result.clone());
+                }
+                ProofOutput::Plain {
+                    proof,
+                    public_inputs,
+                } => {
+                    record.proof_status.proof = Some(proof.clone());
+                    record.proof_status.public_inputs = Some(public_inputs.clone());
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn record_attempt(&self, uuid: uuid::Uuid) -> Result<(), sqlx::Error> {
+        if let Some(record) = self.records.lock().unwrap().get_mut(&uuid) {
+            record.proof_status.attempts += 1;
+        }
+        Ok(())
+    }
+
+    async fn fail_proof(&self, uuid: uuid::Uuid, failure: &Failure) -> Result<(), sqlx::Error> {
+        self.update(uuid, |record| {
+            record.proof_status.status = Status::Failed;
+            record.proof_status.reason = Some(failure.reason.clone());
+            record.proof_status.error_class = failure.error_class.map(str::to_string);
+            record.proof_status.failed_stage = failure.stage;
+            record.proof_status.exit_code = failure.exit_code;
+            record.proof_status.stderr = failure.stderr.clone();
+        });
+        Ok(())
+    }
+
+    async fn cancel_proof(&self, uuid: uuid::Uuid) -> Result<bool, sqlx::Error> {
+        let mut records = self.records.lock().unwrap();
+        match records.get_mut(&uuid) {
+            Some(record) if !record.proof_status.status.is_terminal() => {
+                record.proof_status.status = Status::Cancelled;
+                record.proof_status.reason = Some(CANCELLED_REASON.to_string());
+                record.completed_at = Some(Utc::now());
+                let _ = self.status_updates.send(record.proof_status.clone());
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn get_proof_status(&self, uuid: uuid::Uuid) -> Result<Option<ProofStatus>, String> {
+        Ok(self
+            .records
+            .lock()
+            .unwrap()
+            .get(&uuid)
+            .map(|record| record.proof_status.clone()))
+    }
+
+    async fn get_sealed_result(&self, uuid: uuid::Uuid) -> Result<Option<SealedResult>, String> {
+        Ok(self
+            .records
+            .lock()
+            .unwrap()
+            .get(&uuid)
+            .map(|record| SealedResult {
+                status: record.proof_status.status,
+                encrypted_result: record.proof_status.encrypted_result,
+                sealed_result: record.sealed_result.clone(),
+                purged: record.proof_status.purged_at.is_some(),
+            }))
+    }
+
+    async fn get_unfinished_proofs(
+        &self,
+        instance_id: &str,
+    ) -> Result<Vec<UnfinishedProof>, String> {
+        let records = self.records.lock().unwrap();
+        let mut unfinished: Vec<&Record> = records
+            .values()
+            .filter(|record| {
+                record.instance_id == instance_id && !record.proof_status.status.is_terminal()
+            })
+            .collect();
+        unfinished.sort_by_key(|record| record.proof_status.created_at);
+        Ok(unfinished
+            .into_iter()
+            .map(|record| UnfinishedProof {
+                request_id: record.proof_status.request_id,
+                status: record.proof_status.status,
+                proof_type: record.proof_status.proof_type.clone(),
+                circuit_name: record.proof_status.circuit_name.clone(),
+                encrypted_result: record.proof_status.encrypted_result,
+            })
+            .collect())
+    }
+
+    async fn purge(
+        &self,
+        cutoff: DateTime<Utc>,
+        mode: RetentionMode,
+    ) -> Result<Vec<Status>, sqlx::Error> {
+        let mut records = self.records.lock().unwrap();
+        let expired: Vec<uuid::Uuid> = records
+            .values()
+            .filter(|record| {
+                record.proof_status.status.is_terminal()
+                    && record.proof_status.purged_at.is_none()
+                    && record
+                        .completed_at
+                        .is_some_and(|completed_at| completed_at < cutoff)
+            })
+            .map(|record| record.proof_status.request_id)
+            .collect();
+
+        let mut purged = Vec::with_capacity(expired.len());
+        for uuid in expired {
+            match mode {
+                RetentionMode::Scrub => {
+                    let record = records.get_mut(&uuid).unwrap();
+                    record.proof_status.proof = None;
+                    record.proof_status.public_inputs = None;
+                    record.proof_status.reason = None;
+                    record.proof_status.stderr = None;
+                    record.proof_status.purged_at = Some(Utc::now());
+                    record.sealed_result = None;
+                    purged.push(record.proof_status.status);
+                }
+                RetentionMode::Delete => {
+                    if let Some(record) = records.remove(&uuid) {
+                        purged.push(record.proof_status.status);
+                    }
+                }
+            }
+        }
+        Ok(purged)
+    }
+
+    async fn listen_status_updates(
+        &self,
+        status_update_sender: broadcast::Sender<StatusUpdate>,
+    ) -> Result<(), sqlx::Error> {
+        forward_status_updates(self.status_updates.subscribe(), status_update_sender).await
+    }
+}
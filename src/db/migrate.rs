@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::{Database, Pool};
+
+//the files of ./migrations/<backend>, embedded at build time, migrations applied by a
+//newer binary are accepted so that instances can be rolled one by one
+pub fn postgres() -> Migrator {
+    let mut migrator = sqlx::migrate!("./migrations/postgres");
+    migrator.set_ignore_missing(true);
+    migrator
+}
+
+pub fn sqlite() -> Migrator {
+    let mut migrator = sqlx::migrate!("./migrations/sqlite");
+    migrator.set_ignore_missing(true);
+    migrator
+}
+
+//versions and descriptions of the migrations the database has not applied yet,
+//an error when an applied migration failed halfway or was edited after it was applied
+pub async fn pending<DB>(pool: &Pool<DB>, migrator: &Migrator) -> Result<Vec<(i64, String)>, String>
+where
+    DB: Database,
+    DB::Connection: Migrate,
+{
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+    conn.ensure_migrations_table()
+        .await
+        .map_err(|e| e.to_string())?;
+    if let Some(version) = conn.dirty_version().await.map_err(|e| e.to_string())? {
+        return Err(format!(
+            "migration {} failed partway, fix the database by hand before migrating again",
+            version
+        ));
+    }
+
+    let applied: HashMap<i64, Vec<u8>> = conn
+        .list_applied_migrations()
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|migration| (migration.version, migration.checksum.into_owned()))
+        .collect();
+
+    let mut pending = Vec::new();
+    for migration in migrator.iter() {
+        if migration.migration_type.is_down_migration() {
+            continue;
+        }
+        match applied.get(&migration.version) {
+            Some(checksum) if *checksum != *migration.checksum => {
+                return Err(format!(
+                    "migration {} ({}) was changed after it was applied",
+                    migration.version, migration.description
+                ));
+            }
+            Some(_) => (),
+            None => pending.push((migration.version, migration.description.to_string())),
+        }
+    }
+    Ok(pending)
+}
+
+//applies the pending migrations, returns how many were applied
+pub async fn run<DB>(pool: &Pool<DB>, migrator: &Migrator) -> Result<usize, String>
+where
+    DB: Database,
+    DB::Connection: Migrate,
+{
+    let pending = pending(pool, migrator).await?;
+    migrator.run(pool).await.map_err(|e| e.to_string())?;
+    Ok(pending.len())
+}
+
+//fails when the schema is behind the one this binary was built for
+pub async fn check<DB>(pool: &Pool<DB>, migrator: &Migrator) -> Result<(), String>
+where
+    DB: Database,
+    DB::Connection: Migrate,
+{
+    let pending = pending(pool, migrator).await?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let pending = pending
+        .iter()
+        .map(|(version, description)| format!("{} ({})", version, description))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Err(format!(
+        "the database schema is behind, pending migrations: {}. Run `tee-server migrate` or start with --migrate",
+        pending
+    ))
+}
@@ -1,79 +1,233 @@
+use std::sync::Arc;
+
+use jsonrpsee::core::async_trait;
 use serde::{Deserialize, Serialize};
-use sqlx::types::chrono::Utc;
+use sqlx::types::chrono::{DateTime, Utc};
+use tokio::sync::broadcast;
+use zeroize::Zeroizing;
 
 use crate::{
+    retention::RetentionMode,
+    retry::Transient,
     types::{EndpointType, ProofType},
-    utils::get_tmp_folder_path,
+    utils::{encrypt, get_tmp_folder_path},
 };
+pub mod memory;
+pub mod migrate;
+pub mod postgres;
+pub mod queue;
+pub mod source;
+pub mod sqlite;
+#[cfg(test)]
+mod tests;
 pub mod types;
 
-type PublicInputs = Vec<String>;
+pub type PublicInputs = Vec<String>;
 
-pub async fn create_proof_status(
-    uuid: uuid::Uuid,
-    proof_type: &ProofType,
-    circuit_name: &str,
-    on_chain: bool,
-    db: &sqlx::Pool<sqlx::Postgres>,
-    endpoint_type: Option<&EndpointType>,
-    endpoint: Option<&String>,
-    version: i32,
-    user_defined_data: &str,
-    self_defined_data: &str,
-) -> Result<(), String> {
-    let proof_type_id: i32 = proof_type.into();
-    let now = Utc::now();
-
-    let status: i32 = types::Status::Pending.into();
-
-    let _ = sqlx::query(
-        "INSERT INTO proofs (proof_type, request_id, status, created_at, circuit_name, onchain, endpoint_type, endpoint, version, user_defined_data, self_defined_data) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
-    )
-    .bind(proof_type_id)
-    .bind(sqlx::types::Uuid::from(uuid))
-    .bind(status)
-    .bind(now)
-    .bind(circuit_name)
-    .bind(on_chain)
-    .bind(endpoint_type.map(|e| serde_plain::to_string(e).unwrap()))
-    .bind(endpoint)
-    .bind(version)
-    .bind(user_defined_data)
-    .bind(self_defined_data)
-    .execute(db)
-    .await.map_err(|e| {
-        dbg!(e);
-        return "Could not create the record";
-    })?;
-
-    Ok(())
+pub const CANCELLED_REASON: &str = "cancelled by the client";
+
+#[derive(Debug)]
+pub enum UpdateProofError {
+    //the prover output could not be read or parsed
+    Output(String),
+    Query(sqlx::Error),
 }
 
-pub async fn set_witness_generated(
-    uuid: uuid::Uuid,
-    db: &sqlx::Pool<sqlx::Postgres>,
-) -> Result<(), sqlx::Error> {
-    let status: i32 = types::Status::WitnessGenerated.into();
-    let now = Utc::now();
-
-    match sqlx::query(&format!(
-        "UPDATE proofs SET status = $1, witness_generated_at = $2 WHERE request_id = $3",
-    ))
-    .bind(status)
-    .bind(now)
-    .bind(sqlx::types::Uuid::from(uuid))
-    .execute(db)
-    .await
-    {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            dbg!(&e);
-            return Err(e);
+impl Transient for UpdateProofError {
+    fn is_transient(&self) -> bool {
+        match self {
+            UpdateProofError::Output(_) => false,
+            UpdateProofError::Query(e) => e.is_transient(),
+        }
+    }
+}
+
+impl UpdateProofError {
+    pub fn failure(&self) -> Failure {
+        match self {
+            UpdateProofError::Output(reason) => Failure::new(
+                Some(types::FailureStage::Proof),
+                Some("output"),
+                reason.clone(),
+            ),
+            UpdateProofError::Query(e) => Failure::db(e),
+        }
+    }
+}
+
+impl std::fmt::Display for UpdateProofError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpdateProofError::Output(reason) => write!(f, "{}", reason),
+            UpdateProofError::Query(e) => write!(f, "Could not update proof: {}", e),
+        }
+    }
+}
+
+//the row written when a request is accepted
+pub struct NewProof<'a> {
+    pub uuid: uuid::Uuid,
+    pub proof_type: &'a ProofType,
+    pub circuit_name: &'a str,
+    pub on_chain: bool,
+    pub endpoint_type: Option<&'a EndpointType>,
+    pub endpoint: Option<&'a String>,
+    pub version: i32,
+    pub user_defined_data: &'a str,
+    pub self_defined_data: &'a str,
+    pub instance_id: &'a str,
+    pub encrypt_result: bool,
+}
+
+impl NewProof<'_> {
+    pub fn endpoint_type(&self) -> Option<String> {
+        self.endpoint_type
+            .map(|endpoint_type| serde_plain::to_string(endpoint_type).unwrap())
+    }
+}
+
+//stderr beyond this is dropped, it is sent in the status notifications which are
+//limited to 8000 bytes
+const MAX_STDERR_BYTES: usize = 2048;
+
+//what is recorded about a failed request, reason is a short summary and the other
+//fields let failures be grouped by cause
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub reason: String,
+    pub stage: Option<types::FailureStage>,
+    pub error_class: Option<&'static str>,
+    pub exit_code: Option<i32>,
+    pub stderr: Option<String>,
+}
+
+impl Failure {
+    pub fn new(
+        stage: Option<types::FailureStage>,
+        error_class: Option<&'static str>,
+        reason: impl Into<String>,
+    ) -> Self {
+        Self {
+            reason: reason.into(),
+            stage,
+            error_class,
+            exit_code: None,
+            stderr: None,
+        }
+    }
+
+    //a query that kept failing after its retries
+    pub fn db(error: &sqlx::Error) -> Self {
+        Self::new(Some(types::FailureStage::Db), Some("db"), error.to_string())
+    }
+
+    //keeps the start of stderr, where the circuits write the failed assertion
+    pub fn with_output(mut self, exit_code: Option<i32>, stderr: &str) -> Self {
+        let stderr = stderr.trim();
+        let mut end = stderr.len().min(MAX_STDERR_BYTES);
+        while !stderr.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.exit_code = exit_code;
+        self.stderr = (!stderr.is_empty()).then(|| stderr[..end].to_string());
+        self
+    }
+
+    pub fn stage(&self) -> Option<&'static str> {
+        self.stage.map(|stage| stage.as_str())
+    }
+}
+
+//what is stored for a generated proof, only the sealed bytes when the client asked for it
+pub enum ProofOutput {
+    Plain {
+        proof: Proof,
+        public_inputs: PublicInputs,
+    },
+    Sealed(Vec<u8>),
+}
+
+//the proofs table, writes to a request that reached a terminal status leave it unchanged
+#[async_trait]
+pub trait ProofStore: Send + Sync {
+    //applies the pending migrations of the backend, returns how many were applied
+    async fn migrate(&self) -> Result<usize, String>;
+
+    //fails when the schema is behind the one this binary was built for
+    async fn check_schema(&self) -> Result<(), String>;
+
+    //false when a request with this uuid already exists, so that a replayed submission is not queued twice
+    async fn create_proof_status(&self, proof: NewProof<'_>) -> Result<bool, String>;
+
+    async fn set_witness_generated(&self, uuid: uuid::Uuid) -> Result<(), sqlx::Error>;
+
+    async fn update_proof(&self, uuid: uuid::Uuid, output: &ProofOutput)
+        -> Result<(), sqlx::Error>;
+
+    //counts a retry of one of the pipeline steps of the request
+    async fn record_attempt(&self, uuid: uuid::Uuid) -> Result<(), sqlx::Error>;
+
+    async fn fail_proof(&self, uuid: uuid::Uuid, failure: &Failure) -> Result<(), sqlx::Error>;
+
+    //marks an unfinished request as cancelled, false if it had already reached a terminal status
+    async fn cancel_proof(&self, uuid: uuid::Uuid) -> Result<bool, sqlx::Error>;
+
+    async fn get_proof_status(
+        &self,
+        uuid: uuid::Uuid,
+    ) -> Result<Option<types::ProofStatus>, String>;
+
+    async fn get_sealed_result(
+        &self,
+        uuid: uuid::Uuid,
+    ) -> Result<Option<types::SealedResult>, String>;
+
+    //requests accepted by this instance that have not reached a terminal status
+    async fn get_unfinished_proofs(
+        &self,
+        instance_id: &str,
+    ) -> Result<Vec<types::UnfinishedProof>, String>;
+
+    //scrubs or deletes the requests that reached a terminal status before cutoff,
+    //returns the status of each purged request
+    async fn purge(
+        &self,
+        cutoff: DateTime<Utc>,
+        mode: RetentionMode,
+    ) -> Result<Vec<types::Status>, sqlx::Error>;
+
+    //sends the status of every request that is created or changes status, until the store fails
+    async fn listen_status_updates(
+        &self,
+        status_update_sender: broadcast::Sender<types::StatusUpdate>,
+    ) -> Result<(), sqlx::Error>;
+}
+
+//the store named by the scheme of the database url, memory:// keeps nothing across
+//restarts so that a mistyped url cannot lose the requests of a production server
+pub async fn connect(database_url: &str, dev: bool) -> Result<Arc<dyn ProofStore>, String> {
+    if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+        Ok(Arc::new(
+            postgres::PostgresStore::connect(database_url).await?,
+        ))
+    } else if database_url.starts_with("sqlite:") {
+        Ok(Arc::new(sqlite::SqliteStore::connect(database_url).await?))
+    } else if database_url == "memory://" {
+        if !dev {
+            return Err("memory:// requires --dev".to_string());
         }
+        Ok(Arc::new(memory::MemoryStore::new()))
+    } else {
+        Err("Unsupported database url, expected postgres://, sqlite: or memory://".to_string())
     }
 }
 
-pub async fn update_proof(uuid: uuid::Uuid, db: &sqlx::Pool<sqlx::Postgres>) -> Result<(), String> {
+//stores the prover output, sealed under the result key when the client asked for it
+pub async fn update_proof(
+    uuid: uuid::Uuid,
+    store: &dyn ProofStore,
+    result_key: Option<&[u8; 32]>,
+) -> Result<(), UpdateProofError> {
     let proof_file_path =
         std::path::Path::new(&get_tmp_folder_path(&uuid.to_string())).join("proof.json");
     let public_inputs_file_path =
@@ -84,10 +238,10 @@ pub async fn update_proof(uuid: uuid::Uuid, db: &sqlx::Pool<sqlx::Postgres>) ->
         Ok(proof_string) => proof_string,
         Err(e) => {
             dbg!(&e);
-            return Err(format!(
+            return Err(UpdateProofError::Output(format!(
                 "Could not read proof from path: {}",
                 proof_file_path.display(),
-            ));
+            )));
         }
     };
 
@@ -95,10 +249,10 @@ pub async fn update_proof(uuid: uuid::Uuid, db: &sqlx::Pool<sqlx::Postgres>) ->
         Ok(public_inputs_string) => public_inputs_string,
         Err(e) => {
             dbg!(&e);
-            return Err(format!(
+            return Err(UpdateProofError::Output(format!(
                 "Could not read public inputs from path: {}",
                 public_inputs_file_path.display(),
-            ));
+            )));
         }
     };
 
@@ -107,7 +261,10 @@ pub async fn update_proof(uuid: uuid::Uuid, db: &sqlx::Pool<sqlx::Postgres>) ->
     let proof = match Proof::deserialize(&mut proof_reader) {
         Ok(proof) => proof,
         Err(e) => {
-            return Err(format!("Could not deserialize proof: {}", e));
+            return Err(UpdateProofError::Output(format!(
+                "Could not deserialize proof: {}",
+                e
+            )));
         }
     };
 
@@ -116,54 +273,66 @@ pub async fn update_proof(uuid: uuid::Uuid, db: &sqlx::Pool<sqlx::Postgres>) ->
     let public_inputs = match PublicInputs::deserialize(&mut public_inputs_reader) {
         Ok(public_inputs) => public_inputs,
         Err(e) => {
-            return Err(format!("Could not deserialize public inputs: {}", e));
+            return Err(UpdateProofError::Output(format!(
+                "Could not deserialize public inputs: {}",
+                e
+            )));
         }
     };
 
-    let status: i32 = types::Status::ProofGenererated.into();
-
-    let now = Utc::now();
-    match sqlx::query(
-        "UPDATE proofs SET proof = $1, status = $2, proof_generated_at = $3, public_inputs = $4  WHERE request_id = $5",
-    )
-    .bind(sqlx::types::Json(proof))
-    .bind(status)
-    .bind(now)
-    .bind(public_inputs)
-    .bind(sqlx::types::uuid::Uuid::from(uuid))
-    .execute(db)
-    .await
-    {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            return Err(format!("Could not update proof: {}", e));
+    let output = match result_key {
+        Some(result_key) => {
+            let sealed_result = serde_json::to_vec(&SealedProof {
+                proof: &proof,
+                public_inputs: &public_inputs,
+            })
+            .map(Zeroizing::new)
+            .map_err(|e| e.to_string())
+            .and_then(|plaintext| encrypt(result_key, &plaintext))
+            .map_err(|e| UpdateProofError::Output(format!("Could not seal proof: {}", e)))?;
+            ProofOutput::Sealed(sealed_result.to_bytes())
         }
-    }
+        None => ProofOutput::Plain {
+            proof,
+            public_inputs,
+        },
+    };
+
+    store
+        .update_proof(uuid, &output)
+        .await
+        .map_err(UpdateProofError::Query)
 }
 
-pub async fn fail_proof(
-    uuid: uuid::Uuid,
-    db: &sqlx::Pool<sqlx::Postgres>,
-    reason: String,
+//status updates of the stores without a database trigger, sent by the store itself
+//after every write that creates a request or changes its status
+pub(crate) async fn forward_status_updates(
+    mut receiver: broadcast::Receiver<types::ProofStatus>,
+    status_update_sender: broadcast::Sender<types::StatusUpdate>,
 ) -> Result<(), sqlx::Error> {
-    let status: i32 = types::Status::Failed.into();
-    match sqlx::query("UPDATE proofs SET status = $1, reason = $2 WHERE request_id = $3")
-        .bind(status)
-        .bind(reason)
-        .bind(sqlx::types::Uuid::from(uuid))
-        .execute(db)
-        .await
-    {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            dbg!(&e);
-            return Err(e);
+    loop {
+        //an error only means that nobody is subscribed right now
+        match receiver.recv().await {
+            Ok(proof_status) => {
+                let _ = status_update_sender.send(types::StatusUpdate::Status(proof_status));
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                let _ = status_update_sender.send(types::StatusUpdate::Resync);
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Proof {
+//plaintext of a sealed result
+#[derive(Serialize)]
+struct SealedProof<'a> {
+    proof: &'a Proof,
+    public_inputs: &'a PublicInputs,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Proof {
     pi_a: Vec<String>,
     pi_b: Vec<Vec<String>>,
     pi_c: Vec<String>,
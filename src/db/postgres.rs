@@ -0,0 +1,445 @@
+use jsonrpsee::core::async_trait;
+use sqlx::postgres::{PgListener, PgPoolOptions};
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres, Row};
+use tokio::sync::broadcast;
+
+use super::types::{
+    FailureStage, ProofStatus, SealedResult, Status, StatusNotification, StatusUpdate,
+    UnfinishedProof,
+};
+use super::{
+    migrate, Failure, NewProof, Proof, ProofOutput, ProofStore, PublicInputs, CANCELLED_REASON,
+};
+use crate::retention::RetentionMode;
+use crate::types::ProofType;
+
+pub const STATUS_UPDATE_CHANNEL: &str = "status_update";
+
+//the proofs table of a Postgres database, status updates come from the
+//status_update_notify trigger so that every instance sharing it sees them
+pub struct PostgresStore {
+    pool: Pool<Postgres>,
+}
+
+impl PostgresStore {
+    pub async fn connect(database_url: &str) -> Result<Self, String> {
+        let pool = PgPoolOptions::new()
+            .max_connections(20)
+            .connect(database_url)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl ProofStore for PostgresStore {
+    async fn migrate(&self) -> Result<usize, String> {
+        migrate::run(&self.pool, &migrate::postgres()).await
+    }
+
+    async fn check_schema(&self) -> Result<(), String> {
+        migrate::check(&self.pool, &migrate::postgres()).await
+    }
+
+    async fn create_proof_status(&self, proof: NewProof<'_>) -> Result<bool, String> {
+        let proof_type_id: i32 = proof.proof_type.into();
+        let now = Utc::now();
+
+        let status: i32 = Status::Pending.into();
+
+        let result = sqlx::query(
+            "INSERT INTO proofs (proof_type, request_id, status, created_at, circuit_name, onchain, endpoint_type, endpoint, version, user_defined_data, self_defined_data, instance_id, encrypted_result) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13) ON CONFLICT (request_id) DO NOTHING",
+        )
+        .bind(proof_type_id)
+        .bind(proof.uuid)
+        .bind(status)
+        .bind(now)
+        .bind(proof.circuit_name)
+        .bind(proof.on_chain)
+        .bind(proof.endpoint_type())
+        .bind(proof.endpoint)
+        .bind(proof.version)
+        .bind(proof.user_defined_data)
+        .bind(proof.self_defined_data)
+        .bind(proof.instance_id)
+        .bind(proof.encrypt_result)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            dbg!(e);
+            "Could not create the record"
+        })?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn set_witness_generated(&self, uuid: uuid::Uuid) -> Result<(), sqlx::Error> {
+        let status: i32 = Status::WitnessGenerated.into();
+        let pending: i32 = Status::Pending.into();
+        let witness_generated: i32 = Status::WitnessGenerated.into();
+        let now = Utc::now();
+
+        match sqlx::query(
+            "UPDATE proofs SET status = $1, witness_generated_at = $2 WHERE request_id = $3 AND status IN ($4, $5)",
+        )
+        .bind(status)
+        .bind(now)
+        .bind(uuid)
+        .bind(pending)
+        .bind(witness_generated)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                dbg!(&e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn update_proof(
+        &self,
+        uuid: uuid::Uuid,
+        output: &ProofOutput,
+    ) -> Result<(), sqlx::Error> {
+        let status: i32 = Status::ProofGenererated.into();
+        let pending: i32 = Status::Pending.into();
+        let witness_generated: i32 = Status::WitnessGenerated.into();
+
+        let now = Utc::now();
+        match output {
+            ProofOutput::Sealed(sealed_result) => {
+                sqlx::query(
+                    "UPDATE proofs SET sealed_result = $1, status = $2, proof_generated_at = $3, completed_at = $3 WHERE request_id = $4 AND status IN ($5, $6)",
+                )
+                .bind(sealed_result)
+                .bind(status)
+                .bind(now)
+                .bind(uuid)
+                .bind(pending)
+                .bind(witness_generated)
+                .execute(&self.pool)
+                .await?;
+            }
+            ProofOutput::Plain {
+                proof,
+                public_inputs,
+            } => {
+                sqlx::query(
+                    "UPDATE proofs SET proof = $1, status = $2, proof_generated_at = $3, completed_at = $3, public_inputs = $4  WHERE request_id = $5 AND status IN ($6, $7)",
+                )
+                .bind(sqlx::types::Json(proof))
+                .bind(status)
+                .bind(now)
+                .bind(public_inputs)
+                .bind(uuid)
+                .bind(pending)
+                .bind(witness_generated)
+                .execute(&self.pool)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn record_attempt(&self, uuid: uuid::Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE proofs SET attempts = attempts + 1 WHERE request_id = $1")
+            .bind(uuid)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn fail_proof(&self, uuid: uuid::Uuid, failure: &Failure) -> Result<(), sqlx::Error> {
+        let status: i32 = Status::Failed.into();
+        let pending: i32 = Status::Pending.into();
+        let witness_generated: i32 = Status::WitnessGenerated.into();
+        match sqlx::query(
+            "UPDATE proofs SET status = $1, completed_at = now(), reason = $2, error_class = $3, failed_stage = $4, exit_code = $5, stderr = $6 WHERE request_id = $7 AND status IN ($8, $9)",
+        )
+        .bind(status)
+        .bind(&failure.reason)
+        .bind(failure.error_class)
+        .bind(failure.stage())
+        .bind(failure.exit_code)
+        .bind(&failure.stderr)
+        .bind(uuid)
+        .bind(pending)
+        .bind(witness_generated)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                dbg!(&e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn cancel_proof(&self, uuid: uuid::Uuid) -> Result<bool, sqlx::Error> {
+        let status: i32 = Status::Cancelled.into();
+        let pending: i32 = Status::Pending.into();
+        let witness_generated: i32 = Status::WitnessGenerated.into();
+        let result = sqlx::query(
+            "UPDATE proofs SET status = $1, completed_at = now(), reason = $2 WHERE request_id = $3 AND status IN ($4, $5)",
+        )
+        .bind(status)
+        .bind(CANCELLED_REASON)
+        .bind(uuid)
+        .bind(pending)
+        .bind(witness_generated)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_proof_status(&self, uuid: uuid::Uuid) -> Result<Option<ProofStatus>, String> {
+        let row = match sqlx::query(
+            "SELECT request_id, status, proof_type, circuit_name, created_at, witness_generated_at, proof_generated_at, reason, error_class, failed_stage, exit_code, stderr, attempts, encrypted_result, proof, public_inputs, purged_at FROM proofs WHERE request_id = $1",
+        )
+        .bind(uuid)
+        .fetch_optional(&self.pool)
+        .await
+        {
+            Ok(Some(row)) => row,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                dbg!(&e);
+                return Err("Could not fetch the record".to_string());
+            }
+        };
+
+        let status: i32 = row
+            .try_get::<i16, _>("status")
+            .map_err(|e| e.to_string())?
+            .into();
+        let status = Status::try_from(status).map_err(|_| format!("Unknown status: {}", status))?;
+        let proof_type: i32 = row
+            .try_get::<i16, _>("proof_type")
+            .map_err(|e| e.to_string())?
+            .into();
+        let proof_type = ProofType::try_from(proof_type)
+            .map_err(|_| format!("Unknown proof type: {}", proof_type))?;
+
+        // the proof is only exposed once the pipeline has finished with it
+        let (proof, public_inputs) = if status == Status::ProofGenererated {
+            let proof = row
+                .try_get::<Option<sqlx::types::Json<Proof>>, _>("proof")
+                .map_err(|e| e.to_string())?
+                .map(|proof| proof.0);
+            let public_inputs = row
+                .try_get::<Option<PublicInputs>, _>("public_inputs")
+                .map_err(|e| e.to_string())?;
+            (proof, public_inputs)
+        } else {
+            (None, None)
+        };
+
+        Ok(Some(ProofStatus {
+            request_id: row.try_get("request_id").map_err(|e| e.to_string())?,
+            status,
+            proof_type,
+            circuit_name: row.try_get("circuit_name").map_err(|e| e.to_string())?,
+            created_at: row.try_get("created_at").map_err(|e| e.to_string())?,
+            witness_generated_at: row
+                .try_get("witness_generated_at")
+                .map_err(|e| e.to_string())?,
+            proof_generated_at: row
+                .try_get("proof_generated_at")
+                .map_err(|e| e.to_string())?,
+            reason: row.try_get("reason").map_err(|e| e.to_string())?,
+            error_class: row.try_get("error_class").map_err(|e| e.to_string())?,
+            failed_stage: FailureStage::parse(
+                row.try_get("failed_stage").map_err(|e| e.to_string())?,
+            ),
+            exit_code: row.try_get("exit_code").map_err(|e| e.to_string())?,
+            stderr: row.try_get("stderr").map_err(|e| e.to_string())?,
+            attempts: row
+                .try_get::<i16, _>("attempts")
+                .map_err(|e| e.to_string())?
+                .into(),
+            encrypted_result: row.try_get("encrypted_result").map_err(|e| e.to_string())?,
+            proof,
+            public_inputs,
+            purged_at: row.try_get("purged_at").map_err(|e| e.to_string())?,
+        }))
+    }
+
+    async fn get_sealed_result(&self, uuid: uuid::Uuid) -> Result<Option<SealedResult>, String> {
+        let row = match sqlx::query(
+            "SELECT status, encrypted_result, sealed_result, purged_at IS NOT NULL AS purged FROM proofs WHERE request_id = $1",
+        )
+        .bind(uuid)
+        .fetch_optional(&self.pool)
+        .await
+        {
+            Ok(Some(row)) => row,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                dbg!(&e);
+                return Err("Could not fetch the record".to_string());
+            }
+        };
+
+        let status: i32 = row
+            .try_get::<i16, _>("status")
+            .map_err(|e| e.to_string())?
+            .into();
+        let status = Status::try_from(status).map_err(|_| format!("Unknown status: {}", status))?;
+
+        Ok(Some(SealedResult {
+            status,
+            encrypted_result: row.try_get("encrypted_result").map_err(|e| e.to_string())?,
+            sealed_result: row.try_get("sealed_result").map_err(|e| e.to_string())?,
+            purged: row.try_get("purged").map_err(|e| e.to_string())?,
+        }))
+    }
+
+    async fn get_unfinished_proofs(
+        &self,
+        instance_id: &str,
+    ) -> Result<Vec<UnfinishedProof>, String> {
+        let pending: i32 = Status::Pending.into();
+        let witness_generated: i32 = Status::WitnessGenerated.into();
+
+        let rows = match sqlx::query(
+            "SELECT request_id, status, proof_type, circuit_name, encrypted_result FROM proofs WHERE instance_id = $1 AND status IN ($2, $3) ORDER BY created_at",
+        )
+        .bind(instance_id)
+        .bind(pending)
+        .bind(witness_generated)
+        .fetch_all(&self.pool)
+        .await
+        {
+            Ok(rows) => rows,
+            Err(e) => {
+                dbg!(&e);
+                return Err("Could not fetch unfinished records".to_string());
+            }
+        };
+
+        let mut unfinished = Vec::with_capacity(rows.len());
+        for row in rows {
+            let status: i32 = row
+                .try_get::<i16, _>("status")
+                .map_err(|e| e.to_string())?
+                .into();
+            let status =
+                Status::try_from(status).map_err(|_| format!("Unknown status: {}", status))?;
+            let proof_type: i32 = row
+                .try_get::<i16, _>("proof_type")
+                .map_err(|e| e.to_string())?
+                .into();
+            let proof_type = ProofType::try_from(proof_type)
+                .map_err(|_| format!("Unknown proof type: {}", proof_type))?;
+            unfinished.push(UnfinishedProof {
+                request_id: row.try_get("request_id").map_err(|e| e.to_string())?,
+                status,
+                proof_type,
+                circuit_name: row.try_get("circuit_name").map_err(|e| e.to_string())?,
+                encrypted_result: row.try_get("encrypted_result").map_err(|e| e.to_string())?,
+            });
+        }
+        Ok(unfinished)
+    }
+
+    async fn purge(
+        &self,
+        cutoff: DateTime<Utc>,
+        mode: RetentionMode,
+    ) -> Result<Vec<Status>, sqlx::Error> {
+        let proof_generated: i32 = Status::ProofGenererated.into();
+        let failed: i32 = Status::Failed.into();
+        let cancelled: i32 = Status::Cancelled.into();
+
+        //status, type, circuit, timestamps and failure class stay as anonymised metrics
+        let query = match mode {
+            RetentionMode::Scrub => sqlx::query(
+                "UPDATE proofs SET proof = NULL, public_inputs = NULL, sealed_result = NULL, user_defined_data = NULL, self_defined_data = NULL, endpoint = NULL, endpoint_type = NULL, identifier = NULL, reason = NULL, stderr = NULL, purged_at = now() WHERE completed_at < $1 AND status IN ($2, $3, $4) AND purged_at IS NULL RETURNING status",
+            ),
+            RetentionMode::Delete => sqlx::query(
+                "DELETE FROM proofs WHERE completed_at < $1 AND status IN ($2, $3, $4) RETURNING status",
+            ),
+        };
+        let rows = query
+            .bind(cutoff)
+            .bind(proof_generated)
+            .bind(failed)
+            .bind(cancelled)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter()
+            .map(|row| {
+                let status = row.try_get::<i16, _>("status").map(i32::from)?;
+                Status::try_from(status)
+                    .map_err(|_| sqlx::Error::Decode(format!("Unknown status: {}", status).into()))
+            })
+            .collect()
+    }
+
+    async fn listen_status_updates(
+        &self,
+        status_update_sender: broadcast::Sender<StatusUpdate>,
+    ) -> Result<(), sqlx::Error> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(STATUS_UPDATE_CHANNEL).await?;
+
+        let mut resync = false;
+        loop {
+            //notifications sent while the listener was disconnected are lost, the
+            //receivers are told once it listens again
+            if resync {
+                //a query reconnects the listener if it is not yet
+                if let Err(e) = sqlx::query("SELECT 1").execute(&mut listener).await {
+                    dbg!(&e);
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    continue;
+                }
+                let _ = status_update_sender.send(StatusUpdate::Resync);
+                resync = false;
+            }
+
+            let notification = match listener.try_recv().await {
+                Ok(Some(notification)) => notification,
+                Ok(None) => {
+                    resync = true;
+                    continue;
+                }
+                Err(e) => {
+                    dbg!(&e);
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    resync = true;
+                    continue;
+                }
+            };
+
+            let request_id =
+                match serde_json::from_str::<StatusNotification>(notification.payload()) {
+                    Ok(notification) => notification.request_id,
+                    Err(e) => {
+                        dbg!(&e);
+                        continue;
+                    }
+                };
+
+            //the row may have moved on since, subscribers only need its latest status
+            let proof_status = match self.get_proof_status(request_id).await {
+                Ok(Some(proof_status)) => proof_status,
+                //deleted by the retention purge in between
+                Ok(None) => continue,
+                Err(e) => {
+                    dbg!(&e);
+                    continue;
+                }
+            };
+
+            //an error only means that nobody is subscribed right now
+            let _ = status_update_sender.send(StatusUpdate::Status(proof_status));
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::time::Duration;
+
+use base64::engine::{general_purpose, Engine};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Pool, Postgres, Row};
+use zeroize::Zeroizing;
+
+use super::types::Status;
+use crate::generator::Circuit;
+use crate::types::{EncryptedPayload, ProofType};
+use crate::utils::{decrypt, encrypt};
+
+//a job taken from the queue, leased to this instance until it is completed or its lease runs out
+pub struct ClaimedJob {
+    pub request_id: uuid::Uuid,
+    pub proof_type: ProofType,
+    pub circuit_name: String,
+    //status of the proofs row, a job whose witness was generated may resume at the proof
+    pub status: Status,
+    //how many times the job was claimed, including this time
+    pub claims: i32,
+    sealed_inputs: Vec<u8>,
+    sealed_result_key: Option<Vec<u8>>,
+}
+
+//the jobs table of a Postgres database shared by several instances, each instance claims
+//the jobs it has room for and keeps them by renewing their lease
+pub struct JobQueue {
+    pool: Pool<Postgres>,
+    instance_id: String,
+    lease: Duration,
+    //the circuit inputs hold the passport data, they are only stored sealed
+    key: Zeroizing<[u8; 32]>,
+}
+
+impl JobQueue {
+    pub async fn connect(
+        database_url: &str,
+        instance_id: String,
+        lease: Duration,
+        key: Zeroizing<[u8; 32]>,
+    ) -> Result<Self, String> {
+        if !database_url.starts_with("postgres://") && !database_url.starts_with("postgresql://") {
+            return Err("The database job queue needs a postgres:// database url".to_string());
+        }
+        let pool = PgPoolOptions::new()
+            .max_connections(4)
+            .connect(database_url)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(Self {
+            pool,
+            instance_id,
+            lease,
+            key,
+        })
+    }
+
+    //parses the base64 encoded 32 byte key of the queue
+    pub fn parse_key(value: &str) -> Result<Zeroizing<[u8; 32]>, String> {
+        decode_key(value)
+            .ok_or_else(|| "The job queue key must be 32 base64 encoded bytes".to_string())
+    }
+
+    pub fn lease(&self) -> Duration {
+        self.lease
+    }
+
+    pub async fn enqueue(
+        &self,
+        uuid: uuid::Uuid,
+        proof_type: &ProofType,
+        circuit: &Circuit,
+        result_key: Option<&[u8; 32]>,
+    ) -> Result<(), String> {
+        let proof_type_id: i32 = proof_type.into();
+        let sealed_inputs = encrypt(&self.key, circuit.inputs.as_bytes())?.to_bytes();
+        //sealed as text since only text is read back by decrypt
+        let sealed_result_key = match result_key {
+            Some(result_key) => {
+                let encoded = Zeroizing::new(general_purpose::STANDARD.encode(result_key));
+                Some(encrypt(&self.key, encoded.as_bytes())?.to_bytes())
+            }
+            None => None,
+        };
+
+        sqlx::query(
+            "INSERT INTO jobs (request_id, proof_type, circuit_name, sealed_inputs, sealed_result_key) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(uuid)
+        .bind(proof_type_id)
+        .bind(&circuit.name)
+        .bind(sealed_inputs)
+        .bind(sealed_result_key)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            dbg!(e);
+            "Could not enqueue the job".to_string()
+        })?;
+        Ok(())
+    }
+
+    //leases up to limit jobs of the given circuits that nobody holds, or whose holder stopped
+    //renewing its lease, the oldest first, rows locked by another claim are skipped
+    pub async fn claim(
+        &self,
+        limit: usize,
+        circuit_names: &[String],
+    ) -> Result<Vec<ClaimedJob>, sqlx::Error> {
+        let pending: i32 = Status::Pending.into();
+        let witness_generated: i32 = Status::WitnessGenerated.into();
+
+        //jobs whose request was finished or cancelled elsewhere are not worked on anymore
+        sqlx::query(
+            "DELETE FROM jobs USING proofs WHERE proofs.request_id = jobs.request_id AND proofs.status NOT IN ($1, $2)",
+        )
+        .bind(pending)
+        .bind(witness_generated)
+        .execute(&self.pool)
+        .await?;
+
+        let rows = sqlx::query(
+            "WITH claimable AS (
+                SELECT jobs.request_id FROM jobs
+                WHERE (jobs.leased_by IS NULL OR jobs.lease_expires_at < now())
+                AND jobs.circuit_name = ANY($1)
+                ORDER BY jobs.enqueued_at
+                LIMIT $2
+                FOR UPDATE SKIP LOCKED
+            )
+            UPDATE jobs SET leased_by = $3, lease_expires_at = now() + make_interval(secs => $4), heartbeat_at = now(), claims = jobs.claims + 1
+            FROM claimable, proofs
+            WHERE jobs.request_id = claimable.request_id AND proofs.request_id = jobs.request_id
+            RETURNING jobs.request_id, jobs.proof_type, jobs.circuit_name, jobs.sealed_inputs, jobs.sealed_result_key, jobs.claims, proofs.status",
+        )
+        .bind(circuit_names)
+        .bind(limit as i64)
+        .bind(&self.instance_id)
+        .bind(self.lease.as_secs_f64())
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut claimed = Vec::with_capacity(rows.len());
+        for row in rows {
+            let status: i32 = row.try_get::<Option<i16>, _>("status")?.unwrap_or(0).into();
+            let proof_type: i32 = row.try_get::<i16, _>("proof_type")?.into();
+            let (Ok(status), Ok(proof_type)) =
+                (Status::try_from(status), ProofType::try_from(proof_type))
+            else {
+                dbg!(status, proof_type);
+                continue;
+            };
+            claimed.push(ClaimedJob {
+                request_id: row.try_get("request_id")?,
+                proof_type,
+                circuit_name: row.try_get("circuit_name")?,
+                status,
+                claims: row.try_get::<i16, _>("claims")?.into(),
+                sealed_inputs: row.try_get("sealed_inputs")?,
+                sealed_result_key: row.try_get("sealed_result_key")?,
+            });
+        }
+        Ok(claimed)
+    }
+
+    //the circuit of a claimed job, with the inputs unsealed
+    pub fn circuit(&self, job: &ClaimedJob) -> Result<Circuit, String> {
+        let inputs = self.unseal(&job.sealed_inputs)?;
+        Ok(Circuit {
+            name: job.circuit_name.clone(),
+            inputs: inputs.to_string(),
+        })
+    }
+
+    //the key to seal the result with, set when the client asked for it
+    pub fn result_key(&self, job: &ClaimedJob) -> Result<Option<Zeroizing<[u8; 32]>>, String> {
+        match &job.sealed_result_key {
+            Some(sealed_result_key) => {
+                let encoded = self.unseal(sealed_result_key)?;
+                decode_key(&encoded)
+                    .map(Some)
+                    .ok_or_else(|| "Invalid sealed result key".to_string())
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn unseal(&self, sealed: &[u8]) -> Result<Zeroizing<String>, String> {
+        let payload = EncryptedPayload::from_bytes(sealed)
+            .ok_or_else(|| "Sealed job data is too short".to_string())?;
+        decrypt(
+            &self.key,
+            payload.cipher_text,
+            &payload.auth_tag,
+            &payload.nonce,
+        )
+        .map_err(|_| "Could not unseal the job, the job queue key differs".to_string())
+    }
+
+    //renews the leases of the given jobs, returns the ones this instance does not hold anymore,
+    //because their lease was taken over or their request was cancelled
+    pub async fn heartbeat(&self, uuids: &[uuid::Uuid]) -> Result<Vec<uuid::Uuid>, sqlx::Error> {
+        if uuids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let pending: i32 = Status::Pending.into();
+        let witness_generated: i32 = Status::WitnessGenerated.into();
+
+        let renewed: Vec<uuid::Uuid> = sqlx::query(
+            "UPDATE jobs SET lease_expires_at = now() + make_interval(secs => $1), heartbeat_at = now()
+            FROM proofs
+            WHERE proofs.request_id = jobs.request_id AND proofs.status IN ($2, $3)
+            AND jobs.leased_by = $4 AND jobs.request_id = ANY($5)
+            RETURNING jobs.request_id",
+        )
+        .bind(self.lease.as_secs_f64())
+        .bind(pending)
+        .bind(witness_generated)
+        .bind(&self.instance_id)
+        .bind(uuids)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|row| row.try_get("request_id"))
+        .collect::<Result<_, _>>()?;
+
+        Ok(uuids
+            .iter()
+            .filter(|uuid| !renewed.contains(uuid))
+            .copied()
+            .collect())
+    }
+
+    //removes a job this instance finished with, whatever its outcome
+    pub async fn complete(&self, uuid: uuid::Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM jobs WHERE request_id = $1 AND leased_by = $2")
+            .bind(uuid)
+            .bind(&self.instance_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+fn decode_key(value: &str) -> Option<Zeroizing<[u8; 32]>> {
+    let bytes = Zeroizing::new(general_purpose::STANDARD.decode(value.trim()).ok()?);
+    let key: [u8; 32] = bytes.as_slice().try_into().ok()?;
+    Some(Zeroizing::new(key))
+}
@@ -0,0 +1,77 @@
+use std::path::PathBuf;
+
+use google_cloud_secretmanager_v1::client::SecretManagerService;
+
+use crate::args::Config;
+
+//where the database url is read from at startup
+pub enum DatabaseUrlSource {
+    //GCP Secret Manager, accessed with the attested workload identity
+    SecretManager {
+        project_id: String,
+        secret_id: String,
+    },
+    Url(String),
+    File(PathBuf),
+}
+
+impl DatabaseUrlSource {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        if let Some(database_url) = &config.database_url {
+            return Ok(DatabaseUrlSource::Url(database_url.clone()));
+        }
+        if let Some(database_url_file) = &config.database_url_file {
+            return Ok(DatabaseUrlSource::File(database_url_file.clone()));
+        }
+        if config.dev {
+            return Err(
+                "--dev requires --database-url, DATABASE_URL or --database-url-file".to_string(),
+            );
+        }
+        match &config.project_id {
+            Some(project_id) => Ok(DatabaseUrlSource::SecretManager {
+                project_id: project_id.clone(),
+                secret_id: config.secret_id.clone(),
+            }),
+            None => Err("--project-id or PROJECT_ID is required".to_string()),
+        }
+    }
+
+    pub async fn resolve(&self) -> Result<String, String> {
+        match self {
+            DatabaseUrlSource::SecretManager {
+                project_id,
+                secret_id,
+            } => {
+                let client = SecretManagerService::builder()
+                    .build()
+                    .await
+                    .map_err(|e| format!("Could not create the secret manager client: {}", e))?;
+
+                let name = format!(
+                    "projects/{}/secrets/{}/versions/latest",
+                    project_id, secret_id
+                );
+
+                let resp = client
+                    .access_secret_version()
+                    .set_name(name)
+                    .send()
+                    .await
+                    .map_err(|e| format!("Could not access the secret: {}", e))?;
+
+                let payload = match resp.payload {
+                    Some(payload) => payload.data.to_vec(),
+                    None => return Err("Secret has no payload".to_string()),
+                };
+                //creds is a plain string with the db url
+                String::from_utf8(payload).map_err(|e| e.to_string())
+            }
+            DatabaseUrlSource::Url(database_url) => Ok(database_url.clone()),
+            DatabaseUrlSource::File(path) => match std::fs::read_to_string(path) {
+                Ok(database_url) => Ok(database_url.trim().to_string()),
+                Err(e) => Err(format!("Could not read {}: {}", path.display(), e)),
+            },
+        }
+    }
+}
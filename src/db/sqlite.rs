@@ -0,0 +1,404 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use jsonrpsee::core::async_trait;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteRow};
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::types::Json;
+use sqlx::{Pool, Row, Sqlite};
+use tokio::sync::broadcast;
+
+use super::types::{
+    FailureStage, ProofStatus, SealedResult, Status, StatusUpdate, UnfinishedProof,
+};
+use super::{
+    forward_status_updates, migrate, Failure, NewProof, Proof, ProofOutput, ProofStore,
+    PublicInputs, CANCELLED_REASON,
+};
+use crate::retention::RetentionMode;
+use crate::types::ProofType;
+
+//the proofs table in a SQLite file, for a single server without a Postgres database,
+//status updates are only seen by this process
+pub struct SqliteStore {
+    pool: Pool<Sqlite>,
+    status_updates: broadcast::Sender<ProofStatus>,
+}
+
+impl SqliteStore {
+    pub async fn connect(database_url: &str) -> Result<Self, String> {
+        let options = SqliteConnectOptions::from_str(database_url)
+            .map_err(|e| format!("{:?}", e))?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(Duration::from_secs(5));
+        let pool = SqlitePoolOptions::new()
+            .max_connections(4)
+            .connect_with(options)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        let (status_updates, _) = broadcast::channel(1024);
+        Ok(Self {
+            pool,
+            status_updates,
+        })
+    }
+
+    //stands in for the status_update_notify trigger of the Postgres schema
+    async fn notify(&self, uuid: uuid::Uuid) {
+        match self.get_proof_status(uuid).await {
+            Ok(Some(proof_status)) => {
+                let _ = self.status_updates.send(proof_status);
+            }
+            Ok(None) => (),
+            Err(e) => {
+                dbg!(&e);
+            }
+        }
+    }
+}
+
+fn status(row: &SqliteRow) -> Result<Status, String> {
+    let status: i32 = row.try_get("status").map_err(|e| e.to_string())?;
+    Status::try_from(status).map_err(|_| format!("Unknown status: {}", status))
+}
+
+fn proof_type(row: &SqliteRow) -> Result<ProofType, String> {
+    let proof_type: i32 = row.try_get("proof_type").map_err(|e| e.to_string())?;
+    ProofType::try_from(proof_type).map_err(|_| format!("Unknown proof type: {}", proof_type))
+}
+
+#[async_trait]
+impl ProofStore for SqliteStore {
+    async fn migrate(&self) -> Result<usize, String> {
+        migrate::run(&self.pool, &migrate::sqlite()).await
+    }
+
+    async fn check_schema(&self) -> Result<(), String> {
+        migrate::check(&self.pool, &migrate::sqlite()).await
+    }
+
+    async fn create_proof_status(&self, proof: NewProof<'_>) -> Result<bool, String> {
+        let proof_type_id: i32 = proof.proof_type.into();
+        let status: i32 = Status::Pending.into();
+
+        let result = sqlx::query(
+            "INSERT INTO proofs (proof_type, request_id, status, created_at, circuit_name, onchain, endpoint_type, endpoint, version, user_defined_data, self_defined_data, instance_id, encrypted_result) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (request_id) DO NOTHING",
+        )
+        .bind(proof_type_id)
+        .bind(proof.uuid)
+        .bind(status)
+        .bind(Utc::now())
+        .bind(proof.circuit_name)
+        .bind(proof.on_chain)
+        .bind(proof.endpoint_type())
+        .bind(proof.endpoint)
+        .bind(proof.version)
+        .bind(proof.user_defined_data)
+        .bind(proof.self_defined_data)
+        .bind(proof.instance_id)
+        .bind(proof.encrypt_result)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            dbg!(e);
+            "Could not create the record"
+        })?;
+
+        let created = result.rows_affected() == 1;
+        if created {
+            self.notify(proof.uuid).await;
+        }
+        Ok(created)
+    }
+
+    async fn set_witness_generated(&self, uuid: uuid::Uuid) -> Result<(), sqlx::Error> {
+        let status: i32 = Status::WitnessGenerated.into();
+        let pending: i32 = Status::Pending.into();
+        let witness_generated: i32 = Status::WitnessGenerated.into();
+
+        let result = sqlx::query(
+            "UPDATE proofs SET status = ?, witness_generated_at = ? WHERE request_id = ? AND status IN (?, ?)",
+        )
+        .bind(status)
+        .bind(Utc::now())
+        .bind(uuid)
+        .bind(pending)
+        .bind(witness_generated)
+        .execute(&self.pool)
+        .await?;
+        if result.rows_affected() > 0 {
+            self.notify(uuid).await;
+        }
+        Ok(())
+    }
+
+    async fn update_proof(
+        &self,
+        uuid: uuid::Uuid,
+        output: &ProofOutput,
+    ) -> Result<(), sqlx::Error> {
+        let status: i32 = Status::ProofGenererated.into();
+        let pending: i32 = Status::Pending.into();
+        let witness_generated: i32 = Status::WitnessGenerated.into();
+
+        let now = Utc::now();
+        let result = match output {
+            ProofOutput::Sealed(sealed_result) => {
+                sqlx::query(
+                    "UPDATE proofs SET sealed_result = ?, status = ?, proof_generated_at = ?, completed_at = ? WHERE request_id = ? AND status IN (?, ?)",
+                )
+                .bind(sealed_result)
+                .bind(status)
+                .bind(now)
+                .bind(now)
+                .bind(uuid)
+                .bind(pending)
+                .bind(witness_generated)
+                .execute(&self.pool)
+                .await?
+            }
+            ProofOutput::Plain {
+                proof,
+                public_inputs,
+            } => {
+                sqlx::query(
+                    "UPDATE proofs SET proof = ?, status = ?, proof_generated_at = ?, completed_at = ?, public_inputs = ? WHERE request_id = ? AND status IN (?, ?)",
+                )
+                .bind(Json(proof))
+                .bind(status)
+                .bind(now)
+                .bind(now)
+                .bind(Json(public_inputs))
+                .bind(uuid)
+                .bind(pending)
+                .bind(witness_generated)
+                .execute(&self.pool)
+                .await?
+            }
+        };
+        if result.rows_affected() > 0 {
+            self.notify(uuid).await;
+        }
+        Ok(())
+    }
+
+    async fn record_attempt(&self, uuid: uuid::Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE proofs SET attempts = attempts + 1 WHERE request_id = ?")
+            .bind(uuid)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn fail_proof(&self, uuid: uuid::Uuid, failure: &Failure) -> Result<(), sqlx::Error> {
+        let status: i32 = Status::Failed.into();
+        let pending: i32 = Status::Pending.into();
+        let witness_generated: i32 = Status::WitnessGenerated.into();
+
+        let result = sqlx::query(
+            "UPDATE proofs SET status = ?, completed_at = ?, reason = ?, error_class = ?, failed_stage = ?, exit_code = ?, stderr = ? WHERE request_id = ? AND status IN (?, ?)",
+        )
+        .bind(status)
+        .bind(Utc::now())
+        .bind(&failure.reason)
+        .bind(failure.error_class)
+        .bind(failure.stage())
+        .bind(failure.exit_code)
+        .bind(&failure.stderr)
+        .bind(uuid)
+        .bind(pending)
+        .bind(witness_generated)
+        .execute(&self.pool)
+        .await?;
+        if result.rows_affected() > 0 {
+            self.notify(uuid).await;
+        }
+        Ok(())
+    }
+
+    async fn cancel_proof(&self, uuid: uuid::Uuid) -> Result<bool, sqlx::Error> {
+        let status: i32 = Status::Cancelled.into();
+        let pending: i32 = Status::Pending.into();
+        let witness_generated: i32 = Status::WitnessGenerated.into();
+
+        let result = sqlx::query(
+            "UPDATE proofs SET status = ?, completed_at = ?, reason = ? WHERE request_id = ? AND status IN (?, ?)",
+        )
+        .bind(status)
+        .bind(Utc::now())
+        .bind(CANCELLED_REASON)
+        .bind(uuid)
+        .bind(pending)
+        .bind(witness_generated)
+        .execute(&self.pool)
+        .await?;
+        let cancelled = result.rows_affected() > 0;
+        if cancelled {
+            self.notify(uuid).await;
+        }
+        Ok(cancelled)
+    }
+
+    async fn get_proof_status(&self, uuid: uuid::Uuid) -> Result<Option<ProofStatus>, String> {
+        let row = match sqlx::query(
+            "SELECT request_id, status, proof_type, circuit_name, created_at, witness_generated_at, proof_generated_at, reason, error_class, failed_stage, exit_code, stderr, attempts, encrypted_result, proof, public_inputs, purged_at FROM proofs WHERE request_id = ?",
+        )
+        .bind(uuid)
+        .fetch_optional(&self.pool)
+        .await
+        {
+            Ok(Some(row)) => row,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                dbg!(&e);
+                return Err("Could not fetch the record".to_string());
+            }
+        };
+
+        let status = status(&row)?;
+        // the proof is only exposed once the pipeline has finished with it
+        let (proof, public_inputs) = if status == Status::ProofGenererated {
+            let proof = row
+                .try_get::<Option<Json<Proof>>, _>("proof")
+                .map_err(|e| e.to_string())?
+                .map(|proof| proof.0);
+            let public_inputs = row
+                .try_get::<Option<Json<PublicInputs>>, _>("public_inputs")
+                .map_err(|e| e.to_string())?
+                .map(|public_inputs| public_inputs.0);
+            (proof, public_inputs)
+        } else {
+            (None, None)
+        };
+
+        Ok(Some(ProofStatus {
+            request_id: row.try_get("request_id").map_err(|e| e.to_string())?,
+            status,
+            proof_type: proof_type(&row)?,
+            circuit_name: row.try_get("circuit_name").map_err(|e| e.to_string())?,
+            created_at: row.try_get("created_at").map_err(|e| e.to_string())?,
+            witness_generated_at: row
+                .try_get("witness_generated_at")
+                .map_err(|e| e.to_string())?,
+            proof_generated_at: row
+                .try_get("proof_generated_at")
+                .map_err(|e| e.to_string())?,
+            reason: row.try_get("reason").map_err(|e| e.to_string())?,
+            error_class: row.try_get("error_class").map_err(|e| e.to_string())?,
+            failed_stage: FailureStage::parse(
+                row.try_get("failed_stage").map_err(|e| e.to_string())?,
+            ),
+            exit_code: row.try_get("exit_code").map_err(|e| e.to_string())?,
+            stderr: row.try_get("stderr").map_err(|e| e.to_string())?,
+            attempts: row.try_get("attempts").map_err(|e| e.to_string())?,
+            encrypted_result: row.try_get("encrypted_result").map_err(|e| e.to_string())?,
+            proof,
+            public_inputs,
+            purged_at: row.try_get("purged_at").map_err(|e| e.to_string())?,
+        }))
+    }
+
+    async fn get_sealed_result(&self, uuid: uuid::Uuid) -> Result<Option<SealedResult>, String> {
+        let row = match sqlx::query(
+            "SELECT status, encrypted_result, sealed_result, purged_at IS NOT NULL AS purged FROM proofs WHERE request_id = ?",
+        )
+        .bind(uuid)
+        .fetch_optional(&self.pool)
+        .await
+        {
+            Ok(Some(row)) => row,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                dbg!(&e);
+                return Err("Could not fetch the record".to_string());
+            }
+        };
+
+        Ok(Some(SealedResult {
+            status: status(&row)?,
+            encrypted_result: row.try_get("encrypted_result").map_err(|e| e.to_string())?,
+            sealed_result: row.try_get("sealed_result").map_err(|e| e.to_string())?,
+            purged: row.try_get("purged").map_err(|e| e.to_string())?,
+        }))
+    }
+
+    async fn get_unfinished_proofs(
+        &self,
+        instance_id: &str,
+    ) -> Result<Vec<UnfinishedProof>, String> {
+        let pending: i32 = Status::Pending.into();
+        let witness_generated: i32 = Status::WitnessGenerated.into();
+
+        let rows = match sqlx::query(
+            "SELECT request_id, status, proof_type, circuit_name, encrypted_result FROM proofs WHERE instance_id = ? AND status IN (?, ?) ORDER BY created_at",
+        )
+        .bind(instance_id)
+        .bind(pending)
+        .bind(witness_generated)
+        .fetch_all(&self.pool)
+        .await
+        {
+            Ok(rows) => rows,
+            Err(e) => {
+                dbg!(&e);
+                return Err("Could not fetch unfinished records".to_string());
+            }
+        };
+
+        rows.iter()
+            .map(|row| {
+                Ok(UnfinishedProof {
+                    request_id: row.try_get("request_id").map_err(|e| e.to_string())?,
+                    status: status(row)?,
+                    proof_type: proof_type(row)?,
+                    circuit_name: row.try_get("circuit_name").map_err(|e| e.to_string())?,
+                    encrypted_result: row.try_get("encrypted_result").map_err(|e| e.to_string())?,
+                })
+            })
+            .collect()
+    }
+
+    async fn purge(
+        &self,
+        cutoff: DateTime<Utc>,
+        mode: RetentionMode,
+    ) -> Result<Vec<Status>, sqlx::Error> {
+        let proof_generated: i32 = Status::ProofGenererated.into();
+        let failed: i32 = Status::Failed.into();
+        let cancelled: i32 = Status::Cancelled.into();
+
+        //status, type, circuit, timestamps and failure class stay as anonymised metrics
+        let query = match mode {
+            RetentionMode::Scrub => sqlx::query(
+                "UPDATE proofs SET proof = NULL, public_inputs = NULL, sealed_result = NULL, user_defined_data = NULL, self_defined_data = NULL, endpoint = NULL, endpoint_type = NULL, identifier = NULL, reason = NULL, stderr = NULL, purged_at = ? WHERE completed_at < ? AND status IN (?, ?, ?) AND purged_at IS NULL RETURNING status",
+            )
+            .bind(Utc::now()),
+            RetentionMode::Delete => sqlx::query(
+                "DELETE FROM proofs WHERE completed_at < ? AND status IN (?, ?, ?) RETURNING status",
+            ),
+        };
+        let rows = query
+            .bind(cutoff)
+            .bind(proof_generated)
+            .bind(failed)
+            .bind(cancelled)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter()
+            .map(|row| {
+                let status = row.try_get::<i32, _>("status")?;
+                Status::try_from(status)
+                    .map_err(|_| sqlx::Error::Decode(format!("Unknown status: {}", status).into()))
+            })
+            .collect()
+    }
+
+    async fn listen_status_updates(
+        &self,
+        status_update_sender: broadcast::Sender<StatusUpdate>,
+    ) -> Result<(), sqlx::Error> {
+        forward_status_updates(self.status_updates.subscribe(), status_update_sender).await
+    }
+}
@@ -0,0 +1,395 @@
+use chrono::Duration;
+
+use super::*;
+use crate::db::types::{FailureStage, Status};
+
+//the same checks run against every backend that needs no database server
+async fn stores() -> Vec<(
+    &'static str,
+    Arc<dyn ProofStore>,
+    Option<std::path::PathBuf>,
+)> {
+    let path = std::env::temp_dir().join(format!("tee-server-test-{}.db", uuid::Uuid::new_v4()));
+    let sqlite = sqlite::SqliteStore::connect(&format!("sqlite:{}", path.display()))
+        .await
+        .unwrap();
+    sqlite.migrate().await.unwrap();
+    vec![
+        ("memory", Arc::new(memory::MemoryStore::new()), None),
+        ("sqlite", Arc::new(sqlite), Some(path)),
+    ]
+}
+
+fn remove_sqlite_files(path: Option<std::path::PathBuf>) {
+    if let Some(path) = path {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
+
+async fn create(store: &dyn ProofStore, uuid: uuid::Uuid, instance_id: &str) -> bool {
+    store
+        .create_proof_status(NewProof {
+            uuid,
+            proof_type: &ProofType::Disclose,
+            circuit_name: "vc_and_disclose",
+            on_chain: false,
+            endpoint_type: None,
+            endpoint: None,
+            version: 1,
+            user_defined_data: "user",
+            self_defined_data: "self",
+            instance_id,
+            encrypt_result: false,
+        })
+        .await
+        .unwrap()
+}
+
+async fn status(store: &dyn ProofStore, uuid: uuid::Uuid) -> Status {
+    store.get_proof_status(uuid).await.unwrap().unwrap().status
+}
+
+fn output() -> ProofOutput {
+    ProofOutput::Plain {
+        proof: Proof {
+            pi_a: vec!["1".to_string()],
+            pi_b: vec![vec!["2".to_string()]],
+            pi_c: vec!["3".to_string()],
+            protocol: "groth16".to_string(),
+        },
+        public_inputs: vec!["4".to_string()],
+    }
+}
+
+fn failure() -> Failure {
+    Failure::new(
+        Some(FailureStage::Witness),
+        Some("exited"),
+        "process exited with code 1",
+    )
+    .with_output(Some(1), "assert failed")
+}
+
+#[tokio::test]
+async fn duplicate_request_is_not_created() {
+    for (name, store, path) in stores().await {
+        let uuid = uuid::Uuid::new_v4();
+        assert!(create(&*store, uuid, "a").await, "{}", name);
+        assert!(!create(&*store, uuid, "a").await, "{}", name);
+        assert_eq!(status(&*store, uuid).await, Status::Pending, "{}", name);
+        remove_sqlite_files(path);
+    }
+}
+
+#[tokio::test]
+async fn pipeline_records_each_stage() {
+    for (name, store, path) in stores().await {
+        let uuid = uuid::Uuid::new_v4();
+        create(&*store, uuid, "a").await;
+        store.set_witness_generated(uuid).await.unwrap();
+        assert_eq!(
+            status(&*store, uuid).await,
+            Status::WitnessGenerated,
+            "{}",
+            name
+        );
+        store.update_proof(uuid, &output()).await.unwrap();
+
+        let proof_status = store.get_proof_status(uuid).await.unwrap().unwrap();
+        assert_eq!(proof_status.status, Status::ProofGenererated, "{}", name);
+        assert!(proof_status.proof.is_some(), "{}", name);
+        assert_eq!(
+            proof_status.public_inputs,
+            Some(vec!["4".to_string()]),
+            "{}",
+            name
+        );
+        remove_sqlite_files(path);
+    }
+}
+
+#[tokio::test]
+async fn failure_details_are_recorded() {
+    for (name, store, path) in stores().await {
+        let uuid = uuid::Uuid::new_v4();
+        create(&*store, uuid, "a").await;
+        store.fail_proof(uuid, &failure()).await.unwrap();
+
+        let proof_status = store.get_proof_status(uuid).await.unwrap().unwrap();
+        assert_eq!(proof_status.status, Status::Failed, "{}", name);
+        assert_eq!(
+            proof_status.failed_stage,
+            Some(FailureStage::Witness),
+            "{}",
+            name
+        );
+        assert_eq!(
+            proof_status.error_class.as_deref(),
+            Some("exited"),
+            "{}",
+            name
+        );
+        assert_eq!(proof_status.exit_code, Some(1), "{}", name);
+        assert_eq!(
+            proof_status.stderr.as_deref(),
+            Some("assert failed"),
+            "{}",
+            name
+        );
+        remove_sqlite_files(path);
+    }
+}
+
+#[tokio::test]
+async fn cancel_only_applies_to_unfinished_requests() {
+    for (name, store, path) in stores().await {
+        let pending = uuid::Uuid::new_v4();
+        create(&*store, pending, "a").await;
+        assert!(store.cancel_proof(pending).await.unwrap(), "{}", name);
+        assert_eq!(
+            status(&*store, pending).await,
+            Status::Cancelled,
+            "{}",
+            name
+        );
+        assert!(!store.cancel_proof(pending).await.unwrap(), "{}", name);
+
+        let generated = uuid::Uuid::new_v4();
+        create(&*store, generated, "a").await;
+        store.update_proof(generated, &output()).await.unwrap();
+        assert!(!store.cancel_proof(generated).await.unwrap(), "{}", name);
+        assert_eq!(
+            status(&*store, generated).await,
+            Status::ProofGenererated,
+            "{}",
+            name
+        );
+
+        assert!(
+            !store.cancel_proof(uuid::Uuid::new_v4()).await.unwrap(),
+            "{}",
+            name
+        );
+        remove_sqlite_files(path);
+    }
+}
+
+#[tokio::test]
+async fn terminal_status_is_final() {
+    for (name, store, path) in stores().await {
+        let cancelled = uuid::Uuid::new_v4();
+        create(&*store, cancelled, "a").await;
+        store.cancel_proof(cancelled).await.unwrap();
+
+        let failed = uuid::Uuid::new_v4();
+        create(&*store, failed, "a").await;
+        store.fail_proof(failed, &failure()).await.unwrap();
+
+        let generated = uuid::Uuid::new_v4();
+        create(&*store, generated, "a").await;
+        store.update_proof(generated, &output()).await.unwrap();
+
+        for (uuid, terminal) in [
+            (cancelled, Status::Cancelled),
+            (failed, Status::Failed),
+            (generated, Status::ProofGenererated),
+        ] {
+            store.set_witness_generated(uuid).await.unwrap();
+            assert_eq!(status(&*store, uuid).await, terminal, "{}", name);
+            store.update_proof(uuid, &output()).await.unwrap();
+            assert_eq!(status(&*store, uuid).await, terminal, "{}", name);
+            store
+                .fail_proof(uuid, &Failure::new(None, Some("restart"), "late"))
+                .await
+                .unwrap();
+            assert_eq!(status(&*store, uuid).await, terminal, "{}", name);
+        }
+        let proof_status = store.get_proof_status(failed).await.unwrap().unwrap();
+        assert_eq!(
+            proof_status.error_class.as_deref(),
+            Some("exited"),
+            "{}",
+            name
+        );
+        remove_sqlite_files(path);
+    }
+}
+
+#[tokio::test]
+async fn unfinished_proofs_are_per_instance() {
+    for (name, store, path) in stores().await {
+        let pending = uuid::Uuid::new_v4();
+        create(&*store, pending, "a").await;
+        let witness_generated = uuid::Uuid::new_v4();
+        create(&*store, witness_generated, "a").await;
+        store
+            .set_witness_generated(witness_generated)
+            .await
+            .unwrap();
+        let failed = uuid::Uuid::new_v4();
+        create(&*store, failed, "a").await;
+        store.fail_proof(failed, &failure()).await.unwrap();
+        let other_instance = uuid::Uuid::new_v4();
+        create(&*store, other_instance, "b").await;
+
+        let mut unfinished: Vec<_> = store
+            .get_unfinished_proofs("a")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|proof| (proof.request_id, proof.status))
+            .collect();
+        unfinished.sort_by_key(|(uuid, _)| *uuid);
+        let mut expected = vec![
+            (pending, Status::Pending),
+            (witness_generated, Status::WitnessGenerated),
+        ];
+        expected.sort_by_key(|(uuid, _)| *uuid);
+        assert_eq!(unfinished, expected, "{}", name);
+
+        let unfinished = store.get_unfinished_proofs("b").await.unwrap();
+        assert_eq!(unfinished.len(), 1, "{}", name);
+        assert_eq!(unfinished[0].request_id, other_instance, "{}", name);
+        remove_sqlite_files(path);
+    }
+}
+
+//one request per status, the terminal ones are the purged ones
+async fn requests_of_every_status(store: &dyn ProofStore) -> [uuid::Uuid; 4] {
+    let uuids = [(); 4].map(|_| uuid::Uuid::new_v4());
+    for uuid in uuids {
+        create(store, uuid, "a").await;
+    }
+    let [pending, generated, failed, cancelled] = uuids;
+    store.update_proof(generated, &output()).await.unwrap();
+    store.fail_proof(failed, &failure()).await.unwrap();
+    store.cancel_proof(cancelled).await.unwrap();
+    [pending, generated, failed, cancelled]
+}
+
+fn sorted(statuses: Vec<Status>) -> Vec<i32> {
+    let mut statuses: Vec<i32> = statuses.into_iter().map(i32::from).collect();
+    statuses.sort();
+    statuses
+}
+
+#[tokio::test]
+async fn purge_scrub_keeps_the_rows() {
+    for (name, store, path) in stores().await {
+        let [pending, generated, failed, cancelled] = requests_of_every_status(&*store).await;
+
+        //nothing was created before this cutoff
+        let purged = store
+            .purge(Utc::now() - Duration::hours(1), RetentionMode::Scrub)
+            .await
+            .unwrap();
+        assert!(purged.is_empty(), "{}", name);
+
+        let purged = store
+            .purge(Utc::now() + Duration::minutes(1), RetentionMode::Scrub)
+            .await
+            .unwrap();
+        assert_eq!(
+            sorted(purged),
+            sorted(vec![
+                Status::ProofGenererated,
+                Status::Failed,
+                Status::Cancelled
+            ]),
+            "{}",
+            name
+        );
+
+        let proof_status = store.get_proof_status(generated).await.unwrap().unwrap();
+        assert_eq!(proof_status.status, Status::ProofGenererated, "{}", name);
+        assert!(proof_status.purged_at.is_some(), "{}", name);
+        assert!(proof_status.proof.is_none(), "{}", name);
+        assert!(proof_status.public_inputs.is_none(), "{}", name);
+        let proof_status = store.get_proof_status(failed).await.unwrap().unwrap();
+        assert!(proof_status.reason.is_none(), "{}", name);
+        assert!(proof_status.stderr.is_none(), "{}", name);
+        assert!(
+            store
+                .get_sealed_result(cancelled)
+                .await
+                .unwrap()
+                .unwrap()
+                .purged,
+            "{}",
+            name
+        );
+
+        let proof_status = store.get_proof_status(pending).await.unwrap().unwrap();
+        assert_eq!(proof_status.status, Status::Pending, "{}", name);
+        assert!(proof_status.purged_at.is_none(), "{}", name);
+
+        //scrubbed rows are not purged again
+        let purged = store
+            .purge(Utc::now() + Duration::minutes(1), RetentionMode::Scrub)
+            .await
+            .unwrap();
+        assert!(purged.is_empty(), "{}", name);
+        remove_sqlite_files(path);
+    }
+}
+
+#[tokio::test]
+async fn purge_delete_removes_the_rows() {
+    for (name, store, path) in stores().await {
+        let [pending, generated, failed, cancelled] = requests_of_every_status(&*store).await;
+
+        let purged = store
+            .purge(Utc::now() + Duration::minutes(1), RetentionMode::Delete)
+            .await
+            .unwrap();
+        assert_eq!(purged.len(), 3, "{}", name);
+        for uuid in [generated, failed, cancelled] {
+            assert!(
+                store.get_proof_status(uuid).await.unwrap().is_none(),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(status(&*store, pending).await, Status::Pending, "{}", name);
+        remove_sqlite_files(path);
+    }
+}
+
+#[test]
+fn stderr_is_cut_on_a_char_boundary() {
+    //"é" takes two bytes, the cap falls in the middle of the last one
+    let stderr = format!("a{}", "é".repeat(MAX_STDERR_BYTES));
+    let failure = Failure::new(None, None, "failed").with_output(Some(1), &stderr);
+    let kept = failure.stderr.unwrap();
+    assert_eq!(kept.len(), MAX_STDERR_BYTES - 1);
+    assert!(kept.ends_with('é'));
+
+    let stderr = "é".repeat(MAX_STDERR_BYTES / 2);
+    let failure = Failure::new(None, None, "failed").with_output(Some(1), &stderr);
+    assert_eq!(failure.stderr.unwrap(), stderr);
+
+    let failure = Failure::new(None, None, "failed").with_output(None, " \n");
+    assert!(failure.stderr.is_none());
+}
+
+#[tokio::test]
+async fn purge_counts_from_the_terminal_status() {
+    for (name, store, path) in stores().await {
+        let uuid = uuid::Uuid::new_v4();
+        create(&*store, uuid, "a").await;
+        //created before the cutoff but finished after it
+        let cutoff = Utc::now() + Duration::milliseconds(10);
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        store.update_proof(uuid, &output()).await.unwrap();
+
+        for mode in [RetentionMode::Scrub, RetentionMode::Delete] {
+            let purged = store.purge(cutoff, mode).await.unwrap();
+            assert!(purged.is_empty(), "{}", name);
+        }
+        let proof_status = store.get_proof_status(uuid).await.unwrap().unwrap();
+        assert!(proof_status.proof.is_some(), "{}", name);
+        remove_sqlite_files(path);
+    }
+}
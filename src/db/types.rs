@@ -1,17 +1,137 @@
+use serde::{Deserialize, Serialize};
+use sqlx::types::chrono::{DateTime, Utc};
+
+use crate::types::ProofType;
+
+use super::{Proof, PublicInputs};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Status {
     Pending,
     WitnessGenerated,
+    #[serde(rename = "proof_generated")]
     ProofGenererated,
     Failed,
+    Cancelled,
 }
 
-impl Into<i32> for Status {
-    fn into(self) -> i32 {
-        match self {
+impl From<Status> for i32 {
+    fn from(status: Status) -> i32 {
+        match status {
             Status::Pending => 0,
             Status::WitnessGenerated => 1,
             Status::ProofGenererated => 2,
             Status::Failed => 3,
+            Status::Cancelled => 4,
+        }
+    }
+}
+
+impl TryFrom<i32> for Status {
+    type Error = ();
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Status::Pending),
+            1 => Ok(Status::WitnessGenerated),
+            2 => Ok(Status::ProofGenererated),
+            3 => Ok(Status::Failed),
+            4 => Ok(Status::Cancelled),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Status {
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            Status::ProofGenererated | Status::Failed | Status::Cancelled
+        )
+    }
+}
+
+//the pipeline step a request failed in
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureStage {
+    File,
+    Witness,
+    Proof,
+    Db,
+}
+
+impl FailureStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureStage::File => "file",
+            FailureStage::Witness => "witness",
+            FailureStage::Proof => "proof",
+            FailureStage::Db => "db",
         }
     }
+
+    //the stored text of an unknown stage, written by a newer binary, is read as none
+    pub fn parse(value: Option<String>) -> Option<Self> {
+        value.and_then(|value| serde_plain::from_str(&value).ok())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProofStatus {
+    pub request_id: uuid::Uuid,
+    pub status: Status,
+    pub proof_type: ProofType,
+    pub circuit_name: String,
+    pub created_at: Option<DateTime<Utc>>,
+    pub witness_generated_at: Option<DateTime<Utc>>,
+    pub proof_generated_at: Option<DateTime<Utc>>,
+    pub reason: Option<String>,
+    //set for failures with a known cause, e.g. "timeout"
+    pub error_class: Option<String>,
+    pub failed_stage: Option<FailureStage>,
+    //of the circuit binary or rapidsnark, none when it was killed by a signal
+    pub exit_code: Option<i32>,
+    //what the circuit binary or rapidsnark wrote to stderr, truncated
+    pub stderr: Option<String>,
+    //1 unless a pipeline step was retried after a transient failure
+    pub attempts: i32,
+    //the proof is only available sealed, through openpassport_fetch_result
+    pub encrypted_result: bool,
+    pub proof: Option<Proof>,
+    pub public_inputs: Option<PublicInputs>,
+    //set once the retention task cleared the proof and the request data
+    pub purged_at: Option<DateTime<Utc>>,
+}
+
+pub struct UnfinishedProof {
+    pub request_id: uuid::Uuid,
+    pub status: Status,
+    pub proof_type: ProofType,
+    pub circuit_name: String,
+    pub encrypted_result: bool,
+}
+
+pub struct SealedResult {
+    pub status: Status,
+    pub encrypted_result: bool,
+    pub sealed_result: Option<Vec<u8>>,
+    pub purged: bool,
+}
+
+//what the status update broadcast carries
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum StatusUpdate {
+    Status(ProofStatus),
+    //updates may have been lost, e.g. while the listener reconnected, the receivers
+    //read the rows they follow again
+    Resync,
+}
+
+// payload built by the status_update_notify trigger in migrations/postgres, the
+// proof and public inputs are left out so the row is read again
+#[derive(Deserialize)]
+pub struct StatusNotification {
+    pub request_id: uuid::Uuid,
 }
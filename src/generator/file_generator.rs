@@ -1,29 +1,34 @@
 use std::path;
 
+use crate::scheduler::Prioritized;
 use crate::utils::get_tmp_folder_path;
 
+use super::Circuit;
 use crate::types::{ProofRequest, ProofType};
 use tokio::io::AsyncWriteExt;
 
 pub struct FileGenerator {
     uuid: uuid::Uuid,
-    pub proof_request: ProofRequest,
+    proof_type: ProofType,
+    pub circuit: Circuit,
 }
 
 impl FileGenerator {
     pub fn new(uuid: uuid::Uuid, proof_request: ProofRequest) -> Self {
-        Self {
+        Self::from_circuit(
             uuid,
-            proof_request,
-        }
-    }
-
-    pub fn uuid(&self) -> uuid::Uuid {
-        self.uuid.clone()
+            (&proof_request).into(),
+            proof_request.circuit().clone(),
+        )
     }
 
-    pub fn proof_type(&self) -> ProofType {
-        (&self.proof_request).into()
+    //a job claimed from the database job queue only carries its circuit
+    pub fn from_circuit(uuid: uuid::Uuid, proof_type: ProofType, circuit: Circuit) -> Self {
+        Self {
+            uuid,
+            proof_type,
+            circuit,
+        }
     }
 
     //create the tmp folder
@@ -35,10 +40,22 @@ impl FileGenerator {
 
         let mut input_file = tokio::fs::File::create(path.join("input.json")).await?;
 
-        input_file
-            .write(self.proof_request.circuit().inputs.as_bytes())
-            .await?;
+        input_file.write_all(self.circuit.inputs.as_bytes()).await?;
+
+        Ok((self.uuid, self.circuit.name.clone()))
+    }
+}
+
+impl Prioritized for FileGenerator {
+    fn uuid(&self) -> uuid::Uuid {
+        self.uuid
+    }
+
+    fn proof_type(&self) -> ProofType {
+        self.proof_type.clone()
+    }
 
-        Ok((self.uuid.clone(), self.proof_request.circuit().name.clone()))
+    fn circuit_name(&self) -> &str {
+        &self.circuit.name
     }
 }
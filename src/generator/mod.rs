@@ -2,10 +2,311 @@ pub mod file_generator;
 pub mod proof_generator;
 pub mod witness_generator;
 
+use std::collections::HashMap;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Output, Stdio};
+use std::time::Duration;
+
 use serde::Deserialize;
+use tokio_util::sync::CancellationToken;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::db::{types::FailureStage, Failure};
+use crate::retry::Transient;
 
-#[derive(Debug, Deserialize, Clone)]
+//inputs carry the passport data, they are wiped whenever a copy is dropped
+#[derive(Debug, Deserialize, Clone, Zeroize, ZeroizeOnDrop)]
 pub struct Circuit {
     pub name: String,
     pub inputs: String, //json
 }
+
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    Witness,
+    Proof,
+}
+
+impl From<Stage> for FailureStage {
+    fn from(stage: Stage) -> Self {
+        match stage {
+            Stage::Witness => FailureStage::Witness,
+            Stage::Proof => FailureStage::Proof,
+        }
+    }
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stage::Witness => write!(f, "witness generation"),
+            Stage::Proof => write!(f, "proof generation"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GeneratorError {
+    Timeout {
+        stage: Stage,
+        after: Duration,
+    },
+    //the process could not be started or waited on
+    Io(std::io::Error),
+    //the process failed or wrote to stderr
+    Exited {
+        code: Option<i32>,
+        signal: Option<i32>,
+        stderr: String,
+    },
+    //the request was cancelled by the client
+    Cancelled,
+    Failed(String),
+}
+
+impl GeneratorError {
+    //the stage is the one the generator ran in, a timeout knows its own
+    pub fn failure(&self, stage: FailureStage) -> Failure {
+        match self {
+            GeneratorError::Timeout { stage, .. } => {
+                Failure::new(Some((*stage).into()), Some("timeout"), self.to_string())
+            }
+            GeneratorError::Io(_) => Failure::new(Some(stage), Some("io"), self.to_string()),
+            GeneratorError::Exited {
+                code,
+                signal,
+                stderr,
+            } => {
+                let error_class = if signal.is_some() { "killed" } else { "exited" };
+                Failure::new(Some(stage), Some(error_class), self.to_string())
+                    .with_output(*code, stderr)
+            }
+            GeneratorError::Cancelled => Failure::new(Some(stage), None, self.to_string()),
+            GeneratorError::Failed(_) => {
+                Failure::new(Some(stage), Some("internal"), self.to_string())
+            }
+        }
+    }
+}
+
+impl Transient for GeneratorError {
+    fn is_transient(&self) -> bool {
+        match self {
+            GeneratorError::Io(e) => e.is_transient(),
+            //SIGKILL without a timeout is almost always the OOM killer
+            GeneratorError::Exited { signal, .. } => *signal == Some(libc::SIGKILL),
+            GeneratorError::Timeout { .. }
+            | GeneratorError::Cancelled
+            | GeneratorError::Failed(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for GeneratorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GeneratorError::Timeout { stage, after } => {
+                write!(f, "{} timed out after {}s", stage, after.as_secs())
+            }
+            GeneratorError::Io(e) => write!(f, "{}", e),
+            //stderr is kept apart, see failure
+            GeneratorError::Exited { code, signal, .. } => {
+                if let Some(signal) = signal {
+                    write!(f, "process killed by signal {}", signal)
+                } else if *code != Some(0) {
+                    write!(f, "process exited with code {}", code.unwrap_or(-1))
+                } else {
+                    write!(f, "process wrote to stderr")
+                }
+            }
+            GeneratorError::Cancelled => write!(f, "cancelled"),
+            GeneratorError::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl From<String> for GeneratorError {
+    fn from(reason: String) -> Self {
+        GeneratorError::Failed(reason)
+    }
+}
+
+pub struct StageTimeouts {
+    default: Duration,
+    per_circuit: HashMap<String, Duration>,
+}
+
+impl StageTimeouts {
+    pub fn new(default_secs: u64, per_circuit: &[(String, u64)]) -> Self {
+        Self {
+            default: Duration::from_secs(default_secs),
+            per_circuit: per_circuit
+                .iter()
+                .map(|(circuit_name, secs)| (circuit_name.clone(), Duration::from_secs(*secs)))
+                .collect(),
+        }
+    }
+
+    pub fn for_circuit(&self, circuit_name: &str) -> Duration {
+        self.per_circuit
+            .get(circuit_name)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+//parses a "<circuit_name>=<seconds>" command line value
+pub fn parse_circuit_timeout(value: &str) -> Result<(String, u64), String> {
+    let (circuit_name, secs) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected <circuit_name>=<seconds>, got {}", value))?;
+    let secs = secs
+        .parse::<u64>()
+        .map_err(|e| format!("invalid timeout {}: {}", secs, e))?;
+    Ok((circuit_name.to_string(), secs))
+}
+
+//runs the command in its own process group and kills the whole group once the timeout
+//expires or the request is cancelled
+pub async fn run_with_timeout(
+    command: &mut tokio::process::Command,
+    stage: Stage,
+    timeout: Duration,
+    cancellation: &CancellationToken,
+) -> Result<Output, GeneratorError> {
+    let child = command
+        .process_group(0)
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(GeneratorError::Io)?;
+    let pid = child.id();
+
+    let error = tokio::select! {
+        output = tokio::time::timeout(timeout, child.wait_with_output()) => match output {
+            Ok(output) => return check_output(output.map_err(GeneratorError::Io)?),
+            Err(_) => GeneratorError::Timeout {
+                stage,
+                after: timeout,
+            },
+        },
+        _ = cancellation.cancelled() => GeneratorError::Cancelled,
+    };
+
+    if let Some(pid) = pid {
+        // SAFETY: plain syscall, the negative pid targets the group created above
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+        }
+    }
+    Err(error)
+}
+
+//any output on stderr counts as a failure, the circuits only write there on errors
+pub fn check_output(output: Output) -> Result<Output, GeneratorError> {
+    if !output.status.success() || !output.stderr.is_empty() {
+        return Err(GeneratorError::Exited {
+            code: output.status.code(),
+            signal: output.status.signal(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        });
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    //gone, or a zombie waiting for init to reap it
+    fn is_dead(pid: i32) -> bool {
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat
+                .rsplit_once(") ")
+                .is_some_and(|(_, rest)| rest.starts_with('Z')),
+            Err(_) => true,
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout_kills_the_whole_process_group() {
+        let pid_file =
+            std::env::temp_dir().join(format!("tee-server-test-{}", uuid::Uuid::new_v4()));
+        let mut command = tokio::process::Command::new("sh");
+        command.arg("-c").arg(format!(
+            "sleep 30 & echo $! > {}; sleep 30",
+            pid_file.display()
+        ));
+
+        let started_at = Instant::now();
+        let result = run_with_timeout(
+            &mut command,
+            Stage::Witness,
+            Duration::from_millis(300),
+            &CancellationToken::new(),
+        )
+        .await;
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        let error = result.err().unwrap();
+        assert!(matches!(error, GeneratorError::Timeout { .. }));
+        let failure = error.failure(FailureStage::Proof);
+        assert_eq!(failure.stage, Some(FailureStage::Witness));
+        assert_eq!(failure.error_class, Some("timeout"));
+
+        //the background sleep of the shell was killed with it
+        let pid: i32 = std::fs::read_to_string(&pid_file)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        let _ = std::fs::remove_file(&pid_file);
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !is_dead(pid) && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(is_dead(pid));
+    }
+
+    #[tokio::test]
+    async fn cancellation_stops_the_stage() {
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+        let mut command = tokio::process::Command::new("sleep");
+        command.arg("30");
+
+        let started_at = Instant::now();
+        let result = run_with_timeout(
+            &mut command,
+            Stage::Proof,
+            Duration::from_secs(30),
+            &cancellation,
+        )
+        .await;
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        assert!(matches!(result.err().unwrap(), GeneratorError::Cancelled));
+    }
+
+    #[tokio::test]
+    async fn stderr_output_fails_the_stage() {
+        let mut command = tokio::process::Command::new("sh");
+        command.arg("-c").arg("echo assert failed >&2");
+        let result = run_with_timeout(
+            &mut command,
+            Stage::Witness,
+            Duration::from_secs(5),
+            &CancellationToken::new(),
+        )
+        .await;
+        match result.err().unwrap() {
+            GeneratorError::Exited { code, stderr, .. } => {
+                assert_eq!(code, Some(0));
+                assert_eq!(stderr.trim(), "assert failed");
+            }
+            error => panic!("unexpected error: {}", error),
+        }
+    }
+}
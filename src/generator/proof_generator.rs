@@ -1,32 +1,47 @@
-use core::str;
 use std::path;
+use std::time::Duration;
 
+use tokio_util::sync::CancellationToken;
+
+use super::{run_with_timeout, GeneratorError, Stage};
+use crate::scheduler::Prioritized;
+use crate::types::ProofType;
 use crate::utils::get_tmp_folder_path;
 
 pub struct ProofGenerator {
     uuid: uuid::Uuid,
+    proof_type: ProofType,
+    circuit_name: String,
     zkey_file_path: String,
 }
 
 impl ProofGenerator {
-    pub fn new(uuid: uuid::Uuid, zkey_file_path: String) -> Self {
+    pub fn new(
+        uuid: uuid::Uuid,
+        proof_type: ProofType,
+        circuit_name: String,
+        zkey_file_path: String,
+    ) -> Self {
         ProofGenerator {
             uuid,
+            proof_type,
+            circuit_name,
             zkey_file_path,
         }
     }
 
-    pub fn uuid(&self) -> uuid::Uuid {
-        self.uuid.clone()
-    }
-
-    pub async fn run(&self, rapid_snark_path_exe: &String) -> Result<(), String> {
+    pub async fn run(
+        &self,
+        rapid_snark_path_exe: &String,
+        timeout: Duration,
+        cancellation: &CancellationToken,
+    ) -> Result<(), GeneratorError> {
         // let witness_file_path_str = get_tmp_folder_path(&self.uuid.to_string());
         let tmp_folder_path = get_tmp_folder_path(&self.uuid.to_string());
         let witness_file_path = path::Path::new(&tmp_folder_path).join("output.wtns");
 
         if !witness_file_path.exists() {
-            return Err("Witness file does not exist".to_string());
+            return Err("Witness file does not exist".to_string().into());
         }
 
         // let proof_file_path_str = get_tmp_folder_path(&self.uuid.to_string());
@@ -35,26 +50,32 @@ impl ProofGenerator {
         // let public_inputs = get_tmp_folder_path(&self.uuid);
         let public_inputs = path::Path::new(&tmp_folder_path).join("public_inputs.json");
 
-        match tokio::process::Command::new(format!("{}", rapid_snark_path_exe))
-            .arg(&self.zkey_file_path)
-            .arg(witness_file_path)
-            .arg(proof_file_path)
-            .arg(public_inputs.into_os_string().into_string().unwrap())
-            .output()
-            .await
-        {
-            Ok(output) => {
-                if !output.status.success() || output.stderr.len() > 0 {
-                    return Err(str::from_utf8(&output.stderr)
-                        .unwrap_or("Proof failed")
-                        .to_string());
-                }
-            }
-            Err(err) => {
-                return Err(err.to_string());
-            }
-        };
+        run_with_timeout(
+            tokio::process::Command::new(rapid_snark_path_exe)
+                .arg(&self.zkey_file_path)
+                .arg(witness_file_path)
+                .arg(proof_file_path)
+                .arg(public_inputs.into_os_string().into_string().unwrap()),
+            Stage::Proof,
+            timeout,
+            cancellation,
+        )
+        .await?;
 
         Ok(())
     }
 }
+
+impl Prioritized for ProofGenerator {
+    fn uuid(&self) -> uuid::Uuid {
+        self.uuid
+    }
+
+    fn proof_type(&self) -> ProofType {
+        self.proof_type.clone()
+    }
+
+    fn circuit_name(&self) -> &str {
+        &self.circuit_name
+    }
+}
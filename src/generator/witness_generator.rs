@@ -1,17 +1,24 @@
-use core::str;
 use std::path;
+use std::time::Duration;
 
+use tokio_util::sync::CancellationToken;
+
+use super::{check_output, run_with_timeout, GeneratorError, Stage};
+use crate::scheduler::Prioritized;
+use crate::types::ProofType;
 use crate::utils::get_tmp_folder_path;
 
 pub struct WitnessGenerator {
     pub uuid: uuid::Uuid,
+    proof_type: ProofType,
     circuit_file_name: String,
 }
 
 impl WitnessGenerator {
-    pub fn new(uuid: uuid::Uuid, circuit_file_name: String) -> Self {
+    pub fn new(uuid: uuid::Uuid, proof_type: ProofType, circuit_file_name: String) -> Self {
         WitnessGenerator {
             uuid,
+            proof_type,
             circuit_file_name,
         }
     }
@@ -19,7 +26,9 @@ impl WitnessGenerator {
     pub async fn run(
         &self,
         circuit_folder: &str, //folder where all the circuit executables are
-    ) -> Result<(uuid::Uuid, String), String> {
+        timeout: Duration,
+        cancellation: &CancellationToken,
+    ) -> Result<(uuid::Uuid, String), GeneratorError> {
         let circuit_folder_path = path::Path::new(&circuit_folder);
         let path = circuit_folder_path
             .join(format!("{}_cpp", &self.circuit_file_name))
@@ -27,49 +36,47 @@ impl WitnessGenerator {
 
         if !path.exists() {
             println!("{:?} does not exist", &path);
-            return Err(format!("Circuit not found: {}", path.to_str().unwrap()));
+            return Err(format!("Circuit not found: {}", path.to_str().unwrap()).into());
         }
 
-        let circuit_exe = format!("{}", path.into_os_string().into_string().unwrap());
+        let circuit_exe = path.into_os_string().into_string().unwrap();
 
-        match tokio::process::Command::new("chmod")
+        let output = tokio::process::Command::new("chmod")
             .arg("+x")
             .arg(&circuit_exe)
             .output()
             .await
-        {
-            Ok(output) => {
-                if !output.status.success() || output.stderr.len() > 0 {
-                    let str = str::from_utf8(&output.stderr).unwrap();
-                    return Err(str.to_string());
-                }
-            }
-            Err(err) => {
-                return Err(err.to_string());
-            }
-        }
+            .map_err(GeneratorError::Io)?;
+        check_output(output)?;
 
         let tmp_folder_path = get_tmp_folder_path(&self.uuid.to_string());
         let input_file = tmp_folder_path.clone() + "/input.json";
         let output_file = tmp_folder_path + "/output.wtns";
 
-        let _ = match tokio::process::Command::new(circuit_exe)
-            .arg(&input_file)
-            .arg(&output_file)
-            .output()
-            .await
-        {
-            Ok(output) => {
-                if !output.status.success() || output.stderr.len() > 0 {
-                    let str = str::from_utf8(&output.stderr).unwrap();
-                    return Err(str.to_string());
-                }
-            }
-            Err(err) => {
-                return Err(err.to_string());
-            }
-        };
+        run_with_timeout(
+            tokio::process::Command::new(circuit_exe)
+                .arg(&input_file)
+                .arg(&output_file),
+            Stage::Witness,
+            timeout,
+            cancellation,
+        )
+        .await?;
+
+        Ok((self.uuid, self.circuit_file_name.clone()))
+    }
+}
+
+impl Prioritized for WitnessGenerator {
+    fn uuid(&self) -> uuid::Uuid {
+        self.uuid
+    }
+
+    fn proof_type(&self) -> ProofType {
+        self.proof_type.clone()
+    }
 
-        Ok((self.uuid.clone(), self.circuit_file_name.clone()))
+    fn circuit_name(&self) -> &str {
+        &self.circuit_file_name
     }
 }
@@ -0,0 +1,460 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use chrono::{DateTime, Utc};
+use hkdf::Hkdf;
+use p256::ecdh::diffie_hellman;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use tokio::sync::OnceCell;
+use zeroize::Zeroizing;
+
+use crate::attestation::{AttestationProvider, AttestationRequest};
+use crate::key_schedule::SessionKeys;
+
+//RFC 9180 base mode, DHKEM(P-256, HKDF-SHA256) + HKDF-SHA256 + AES-256-GCM
+const KEM_ID: u16 = 0x0010;
+const KDF_ID: u16 = 0x0001;
+const AEAD_ID: u16 = 0x0002;
+const MODE_BASE: u8 = 0x00;
+const ENC_LEN: usize = 65;
+
+//info of the submit_sealed key schedule, the request uuid goes in the aad
+pub const SUBMIT_SEALED_INFO: &[u8] = b"openpassport submit_sealed";
+
+fn kem_suite_id() -> Vec<u8> {
+    [b"KEM".as_slice(), &KEM_ID.to_be_bytes()].concat()
+}
+
+fn hpke_suite_id(aead_id: u16) -> Vec<u8> {
+    [
+        b"HPKE".as_slice(),
+        &KEM_ID.to_be_bytes(),
+        &KDF_ID.to_be_bytes(),
+        &aead_id.to_be_bytes(),
+    ]
+    .concat()
+}
+
+fn labeled_extract(suite_id: &[u8], salt: &[u8], label: &[u8], ikm: &[u8]) -> Zeroizing<[u8; 32]> {
+    let labeled_ikm = Zeroizing::new([b"HPKE-v1".as_slice(), suite_id, label, ikm].concat());
+    let (prk, _) = Hkdf::<Sha256>::extract(Some(salt), &labeled_ikm);
+    Zeroizing::new(prk.into())
+}
+
+fn labeled_expand(suite_id: &[u8], prk: &[u8; 32], label: &[u8], info: &[u8], okm: &mut [u8]) {
+    let length = (okm.len() as u16).to_be_bytes();
+    let labeled_info = [length.as_slice(), b"HPKE-v1", suite_id, label, info].concat();
+    Hkdf::<Sha256>::from_prk(prk)
+        .expect("32 bytes is a valid HKDF-SHA256 prk")
+        .expand(&labeled_info, okm)
+        .expect("HPKE outputs are valid HKDF-SHA256 output lengths");
+}
+
+//receiver context of a single message
+struct Context {
+    suite_id: Vec<u8>,
+    key: Zeroizing<Vec<u8>>,
+    base_nonce: Zeroizing<[u8; 12]>,
+    exporter_secret: Zeroizing<[u8; 32]>,
+}
+
+impl Context {
+    //KeySchedule of the base mode, key_len is the Nk of the AEAD
+    fn new(aead_id: u16, key_len: usize, shared_secret: &[u8; 32], info: &[u8]) -> Self {
+        let suite_id = hpke_suite_id(aead_id);
+        let psk_id_hash = labeled_extract(&suite_id, b"", b"psk_id_hash", b"");
+        let info_hash = labeled_extract(&suite_id, b"", b"info_hash", info);
+        let key_schedule_context = [
+            [MODE_BASE].as_slice(),
+            psk_id_hash.as_ref(),
+            info_hash.as_ref(),
+        ]
+        .concat();
+        let secret = labeled_extract(&suite_id, shared_secret, b"secret", b"");
+
+        let mut context = Context {
+            key: Zeroizing::new(vec![0u8; key_len]),
+            base_nonce: Zeroizing::new([0u8; 12]),
+            exporter_secret: Zeroizing::new([0u8; 32]),
+            suite_id,
+        };
+        labeled_expand(
+            &context.suite_id,
+            &secret,
+            b"key",
+            &key_schedule_context,
+            context.key.as_mut(),
+        );
+        labeled_expand(
+            &context.suite_id,
+            &secret,
+            b"base_nonce",
+            &key_schedule_context,
+            context.base_nonce.as_mut(),
+        );
+        labeled_expand(
+            &context.suite_id,
+            &secret,
+            b"exp",
+            &key_schedule_context,
+            context.exporter_secret.as_mut(),
+        );
+        context
+    }
+
+    fn export(&self, exporter_context: &[u8]) -> Zeroizing<[u8; 32]> {
+        let mut secret = Zeroizing::new([0u8; 32]);
+        labeled_expand(
+            &self.suite_id,
+            &self.exporter_secret,
+            b"sec",
+            exporter_context,
+            secret.as_mut(),
+        );
+        secret
+    }
+}
+
+//a long-lived key pair clients seal their request to in one round trip
+pub struct HpkeKey {
+    pub key_id: u32,
+    secret_key: SecretKey,
+    //uncompressed SEC1 point, the serialization RFC 9180 uses for P-256
+    pub public_key: Vec<u8>,
+    pub expires_at: DateTime<Utc>,
+    //produced on first use, so that a key nobody asks for is never attested
+    attestation: OnceCell<Vec<u8>>,
+    //encapsulated keys of the requests accepted under this key, a replay is
+    //rejected even after its proofs row has been purged, cleared when the key
+    //rotates out as it then rejects everything
+    consumed: Mutex<HashSet<Vec<u8>>>,
+}
+
+impl HpkeKey {
+    fn generate(key_id: u32, lifetime: Duration) -> Self {
+        Self::from_secret_key(key_id, SecretKey::random(&mut OsRng), lifetime)
+    }
+
+    fn from_secret_key(key_id: u32, secret_key: SecretKey, lifetime: Duration) -> Self {
+        let public_key = secret_key
+            .public_key()
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec();
+        Self {
+            key_id,
+            secret_key,
+            public_key,
+            expires_at: Utc::now()
+                + chrono::Duration::from_std(lifetime).unwrap_or(chrono::Duration::MAX),
+            attestation: OnceCell::new(),
+            consumed: Mutex::new(HashSet::new()),
+        }
+    }
+
+    //the compressed key is bound as public_key, so that it fits a Confidential Space nonce
+    pub async fn attestation(
+        &self,
+        attestation_provider: &dyn AttestationProvider,
+    ) -> Result<Vec<u8>, String> {
+        self.attestation
+            .get_or_try_init(|| async {
+                attestation_provider
+                    .attest(AttestationRequest {
+                        user_data: None,
+                        nonce: None,
+                        public_key: Some(
+                            self.secret_key
+                                .public_key()
+                                .to_encoded_point(true)
+                                .as_bytes()
+                                .to_vec(),
+                        ),
+                    })
+                    .await
+                    .map_err(|e| format!("{:?}", e))
+            })
+            .await
+            .cloned()
+    }
+
+    //marks enc as used, false when a request was already accepted with it
+    pub fn try_consume(&self, enc: &[u8]) -> bool {
+        self.consumed.lock().unwrap().insert(enc.to_vec())
+    }
+
+    //lets the client retry a request that was not accepted
+    pub fn release(&self, enc: &[u8]) {
+        self.consumed.lock().unwrap().remove(enc);
+    }
+
+    //Decap of the receiver, returns the shared secret
+    fn decap(&self, enc: &[u8]) -> Result<Zeroizing<[u8; 32]>, String> {
+        if enc.len() != ENC_LEN {
+            return Err(format!("Encapsulated key must be {} bytes", ENC_LEN));
+        }
+        let ephemeral_public_key = PublicKey::from_sec1_bytes(enc).map_err(|e| e.to_string())?;
+
+        let dh = diffie_hellman(
+            self.secret_key.to_nonzero_scalar(),
+            ephemeral_public_key.as_affine(),
+        );
+        let kem_suite_id = kem_suite_id();
+        let kem_context = [enc, self.public_key.as_slice()].concat();
+        let eae_prk = labeled_extract(
+            &kem_suite_id,
+            b"",
+            b"eae_prk",
+            dh.raw_secret_bytes().as_ref(),
+        );
+        let mut shared_secret = Zeroizing::new([0u8; 32]);
+        labeled_expand(
+            &kem_suite_id,
+            &eae_prk,
+            b"shared_secret",
+            &kem_context,
+            shared_secret.as_mut(),
+        );
+
+        Ok(shared_secret)
+    }
+
+    //single-shot Open, returns the plaintext and the keys cancel and fetch_result use
+    //for this request, exported from the HPKE context
+    pub fn open(
+        &self,
+        enc: &[u8],
+        info: &[u8],
+        aad: &[u8],
+        cipher_text: &[u8],
+    ) -> Result<(Zeroizing<Vec<u8>>, SessionKeys), String> {
+        let shared_secret = self.decap(enc)?;
+        let context = Context::new(AEAD_ID, 32, &shared_secret, info);
+
+        let key = Key::<Aes256Gcm>::from_slice(&context.key);
+        let plaintext = Aes256Gcm::new(key)
+            .decrypt(
+                Nonce::from_slice(context.base_nonce.as_ref()),
+                Payload {
+                    msg: cipher_text,
+                    aad,
+                },
+            )
+            .map_err(|e| e.to_string())?;
+
+        let session_keys = SessionKeys {
+            request_key: context.export(b"openpassport request key"),
+            response_key: context.export(b"openpassport response key"),
+        };
+        Ok((Zeroizing::new(plaintext), session_keys))
+    }
+}
+
+pub struct HpkeKeyRing {
+    //newest first, the previous key is still accepted for one rotation period
+    keys: RwLock<Vec<Arc<HpkeKey>>>,
+    rotation: Duration,
+}
+
+impl HpkeKeyRing {
+    pub fn new(rotation: Duration) -> Self {
+        let key_ring = Self {
+            keys: RwLock::new(Vec::new()),
+            rotation,
+        };
+        key_ring.rotate();
+        key_ring
+    }
+
+    pub fn rotate(&self) {
+        let mut keys = self.keys.write().unwrap();
+        let mut key_id = OsRng.next_u32();
+        while keys.iter().any(|key| key.key_id == key_id) {
+            key_id = OsRng.next_u32();
+        }
+        keys.insert(0, Arc::new(HpkeKey::generate(key_id, self.rotation * 2)));
+        while keys.len() > 2 {
+            if let Some(key) = keys.pop() {
+                key.consumed.lock().unwrap().clear();
+            }
+        }
+    }
+
+    pub fn current(&self) -> Arc<HpkeKey> {
+        Arc::clone(&self.keys.read().unwrap()[0])
+    }
+
+    pub fn get(&self, key_id: u32) -> Option<Arc<HpkeKey>> {
+        let now = Utc::now();
+        self.keys
+            .read()
+            .unwrap()
+            .iter()
+            .find(|key| key.key_id == key_id && key.expires_at > now)
+            .cloned()
+    }
+
+    pub fn spawn_rotation(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.rotation);
+            //the first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                self.rotate();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::Aes128Gcm;
+
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    //RFC 9180 A.3.1, DHKEM(P-256, HKDF-SHA256), HKDF-SHA256, AES-128-GCM, base mode,
+    //the KEM and KDF are the ones used here and the key schedule only differs in Nk
+    const INFO: &str = "4f6465206f6e2061204772656369616e2055726e";
+    const SK_RM: &str = "f3ce7fdae57e1a310d87f1ebbde6f328be0a99cdbcadf4d6589cf29de4b8ffd2";
+    const PK_RM: &str = "04fe8c19ce0905191ebc298a9245792531f26f0cece2460639e8bc39cb7f706a826a779b4cf969b8a0e539c7f62fb3d30ad6aa8f80e30f1d128aafd68a2ce72ea0";
+    const ENC: &str = "04a92719c6195d5085104f469a8b9814d5838ff72b60501e2c4466e5e67b325ac98536d7b61a1af4b78e5b7f951c0900be863c403ce65c9bfcb9382657222d18c4";
+    const SHARED_SECRET: &str = "c0d26aeab536609a572b07695d933b589dcf363ff9d93c93adea537aeabb8cb8";
+
+    fn receiver_key() -> HpkeKey {
+        let secret_key = SecretKey::from_slice(&hex(SK_RM)).unwrap();
+        HpkeKey::from_secret_key(1, secret_key, Duration::from_secs(60))
+    }
+
+    fn shared_secret() -> [u8; 32] {
+        hex(SHARED_SECRET).try_into().unwrap()
+    }
+
+    #[test]
+    fn decap_matches_rfc_9180_vector() {
+        let key = receiver_key();
+        assert_eq!(key.public_key, hex(PK_RM));
+        assert_eq!(key.decap(&hex(ENC)).unwrap().as_ref(), &shared_secret());
+    }
+
+    #[test]
+    fn decap_rejects_malformed_enc() {
+        let key = receiver_key();
+        assert!(key.decap(&hex(PK_RM)[..33]).is_err());
+        assert!(key.decap(&[4u8; ENC_LEN]).is_err());
+    }
+
+    #[test]
+    fn key_schedule_matches_rfc_9180_vector() {
+        let context = Context::new(0x0001, 16, &shared_secret(), &hex(INFO));
+        assert_eq!(*context.key, hex("868c066ef58aae6dc589b6cfdd18f97e"));
+        assert_eq!(context.base_nonce.to_vec(), hex("4e0bc5018beba4bf004cca59"));
+        assert_eq!(
+            context.exporter_secret.to_vec(),
+            hex("14ad94af484a7ad3ef40e9f3be99ecc6fa9036df9d4920548424df127ee0d99f")
+        );
+
+        //sequence number 0 uses the base nonce as is
+        let cipher_text = Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&context.key))
+            .encrypt(
+                Nonce::from_slice(context.base_nonce.as_ref()),
+                Payload {
+                    msg: &hex("4265617574792069732074727574682c20747275746820626561757479"),
+                    aad: &hex("436f756e742d30"),
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            cipher_text,
+            hex("5ad590bb8baa577f8619db35a36311226a896e7342a6d836d8b7bcd2f20b6c7f9076ac232e3ab2523f39513434")
+        );
+    }
+
+    #[test]
+    fn exporter_matches_rfc_9180_vector() {
+        let context = Context::new(0x0001, 16, &shared_secret(), &hex(INFO));
+        for (exporter_context, exported) in [
+            (
+                "",
+                "5e9bc3d236e1911d95e65b576a8a86d478fb827e8bdfe77b741b289890490d4d",
+            ),
+            (
+                "00",
+                "6cff87658931bda83dc857e6353efe4987a201b849658d9b047aab4cf216e796",
+            ),
+            (
+                "54657374436f6e74657874",
+                "d8f1ea7942adbba7412c6d431c62d01371ea476b823eb697e1f6e6cae1dab85a",
+            ),
+        ] {
+            assert_eq!(
+                context.export(&hex(exporter_context)).to_vec(),
+                hex(exported)
+            );
+        }
+    }
+
+    #[test]
+    fn open_roundtrip_and_wrong_aad() {
+        let key = receiver_key();
+        let context = Context::new(AEAD_ID, 32, &shared_secret(), SUBMIT_SEALED_INFO);
+        let cipher_text = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&context.key))
+            .encrypt(
+                Nonce::from_slice(context.base_nonce.as_ref()),
+                Payload {
+                    msg: b"request",
+                    aad: b"uuid",
+                },
+            )
+            .unwrap();
+
+        let (plaintext, session_keys) = key
+            .open(&hex(ENC), SUBMIT_SEALED_INFO, b"uuid", &cipher_text)
+            .unwrap();
+        assert_eq!(plaintext.as_slice(), b"request");
+        assert_eq!(
+            session_keys.request_key,
+            context.export(b"openpassport request key")
+        );
+        assert!(key
+            .open(&hex(ENC), SUBMIT_SEALED_INFO, b"other", &cipher_text)
+            .is_err());
+    }
+
+    #[test]
+    fn enc_is_consumed_once() {
+        let key = receiver_key();
+        assert!(key.try_consume(&hex(ENC)));
+        assert!(!key.try_consume(&hex(ENC)));
+        key.release(&hex(ENC));
+        assert!(key.try_consume(&hex(ENC)));
+    }
+
+    #[test]
+    fn rotated_out_key_forgets_consumed() {
+        let key_ring = HpkeKeyRing::new(Duration::from_secs(60));
+        let first = key_ring.current();
+        assert!(first.try_consume(&hex(ENC)));
+
+        key_ring.rotate();
+        assert!(key_ring.get(first.key_id).is_some());
+        assert!(!first.try_consume(&hex(ENC)));
+
+        key_ring.rotate();
+        assert!(key_ring.get(first.key_id).is_none());
+        assert!(first.consumed.lock().unwrap().is_empty());
+    }
+}
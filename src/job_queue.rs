@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+use crate::db::queue::{ClaimedJob, JobQueue};
+use crate::db::{types::Status, Failure, ProofStore};
+use crate::generator::{file_generator::FileGenerator, proof_generator::ProofGenerator};
+use crate::jobs::JobRegistry;
+use crate::scheduler::PrioritySender;
+use crate::utils::{cleanup, get_tmp_folder_path};
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum JobQueueKind {
+    /// In process, jobs are worked on by the instance that accepted them
+    Local,
+    /// The jobs table of the Postgres database, shared by every instance using it
+    Database,
+}
+
+//takes jobs from the queue whenever this instance holds fewer than capacity of them
+#[allow(clippy::too_many_arguments)]
+pub async fn claim_jobs(
+    queue: Arc<JobQueue>,
+    store: Arc<dyn ProofStore>,
+    circuit_zkey_map: Arc<HashMap<String, String>>,
+    jobs: Arc<JobRegistry>,
+    file_generator_sender: PrioritySender<FileGenerator>,
+    proof_generator_sender: PrioritySender<ProofGenerator>,
+    capacity: usize,
+    max_claims: u32,
+    poll_interval: Duration,
+) {
+    //only jobs of circuits this instance has a zkey for
+    let circuit_names: Vec<String> = circuit_zkey_map.keys().cloned().collect();
+    loop {
+        let room = capacity.saturating_sub(jobs.leased().len());
+        let claimed = if room > 0 {
+            match queue.claim(room, &circuit_names).await {
+                Ok(claimed) => claimed,
+                Err(e) => {
+                    dbg!(&e);
+                    Vec::new()
+                }
+            }
+        } else {
+            Vec::new()
+        };
+        if claimed.is_empty() {
+            tokio::time::sleep(poll_interval).await;
+            continue;
+        }
+
+        for job in claimed {
+            let uuid = job.request_id;
+            if job.claims > max_claims as i32 {
+                let reason = format!(
+                    "job abandoned after {} claims, the instances holding it stopped",
+                    job.claims - 1
+                );
+                let failure = Failure::new(None, Some("abandoned"), reason);
+                fail(&queue, &*store, uuid, failure).await;
+                continue;
+            }
+            if let Err(e) = resume(
+                &queue,
+                job,
+                &circuit_zkey_map,
+                &jobs,
+                &file_generator_sender,
+                &proof_generator_sender,
+            )
+            .await
+            {
+                fail(&queue, &*store, uuid, Failure::new(None, Some("queue"), e)).await;
+            }
+        }
+    }
+}
+
+//starts a claimed job at the file generation, or at the proof if this instance already
+//generated its witness before it lost the job
+async fn resume(
+    queue: &JobQueue,
+    job: ClaimedJob,
+    circuit_zkey_map: &HashMap<String, String>,
+    jobs: &JobRegistry,
+    file_generator_sender: &PrioritySender<FileGenerator>,
+    proof_generator_sender: &PrioritySender<ProofGenerator>,
+) -> Result<(), String> {
+    let circuit = queue.circuit(&job)?;
+    let result_key = queue.result_key(&job)?;
+    let zkey_file_path = circuit_zkey_map
+        .get(&job.circuit_name)
+        .ok_or_else(|| format!("Unknown circuit: {}", job.circuit_name))?;
+
+    jobs.claim(job.request_id, result_key);
+    let witness_file_path =
+        path::Path::new(&get_tmp_folder_path(&job.request_id.to_string())).join("output.wtns");
+    if job.status == Status::WitnessGenerated && witness_file_path.exists() {
+        proof_generator_sender
+            .send(ProofGenerator::new(
+                job.request_id,
+                job.proof_type,
+                job.circuit_name,
+                zkey_file_path.clone(),
+            ))
+            .await;
+    } else {
+        file_generator_sender
+            .send(FileGenerator::from_circuit(
+                job.request_id,
+                job.proof_type,
+                circuit,
+            ))
+            .await;
+    }
+    Ok(())
+}
+
+async fn fail(queue: &JobQueue, store: &dyn ProofStore, uuid: uuid::Uuid, failure: Failure) {
+    cleanup(uuid, store, failure).await;
+    if let Err(e) = queue.complete(uuid).await {
+        dbg!(&e);
+    }
+}
+
+//keeps the leases of the jobs this instance works on, a job whose lease was lost or whose
+//request was cancelled is stopped like a cancelled one
+pub async fn renew_leases(queue: Arc<JobQueue>, jobs: Arc<JobRegistry>) {
+    let interval = (queue.lease() / 3).max(Duration::from_secs(1));
+    loop {
+        tokio::time::sleep(interval).await;
+        let lost = match queue.heartbeat(&jobs.leased()).await {
+            Ok(lost) => lost,
+            Err(e) => {
+                dbg!(&e);
+                continue;
+            }
+        };
+        for uuid in lost {
+            println!("Lost the lease of job {}", uuid);
+            jobs.cancel(uuid).await;
+        }
+    }
+}
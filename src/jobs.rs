@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{broadcast, OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+use zeroize::Zeroizing;
+
+use crate::db::queue::JobQueue;
+use crate::db::types::StatusUpdate;
+use crate::db::ProofStore;
+use crate::generator::{
+    file_generator::FileGenerator, proof_generator::ProofGenerator,
+    witness_generator::WitnessGenerator,
+};
+use crate::key_schedule::SessionKeys;
+use crate::scheduler::{Prioritized, PrioritySender};
+use crate::types::{QueueDepth, QueueDepths};
+use crate::utils::remove_tmp_folder;
+
+struct Job {
+    //request key of the hello session, kept so that the client can authenticate
+    //a cancel, None for jobs resumed after a restart
+    request_key: Option<Zeroizing<[u8; 32]>>,
+    //set when the client asked for its result to be sealed
+    result_key: Option<Zeroizing<[u8; 32]>>,
+    cancellation: CancellationToken,
+    //returned to the admission limit when the job is removed
+    _admission: Option<OwnedSemaphorePermit>,
+    //claimed from the database job queue, so this instance works on it
+    leased: bool,
+}
+
+//the server already holds as many jobs as it accepts, the client should come back after retry_after
+pub struct QueueFull {
+    pub retry_after: Duration,
+}
+
+//jobs between submit_request and their terminal status
+pub struct JobRegistry {
+    jobs: Mutex<HashMap<uuid::Uuid, Job>>,
+    file_generator_sender: PrioritySender<FileGenerator>,
+    witness_generator_sender: PrioritySender<WitnessGenerator>,
+    proof_generator_sender: PrioritySender<ProofGenerator>,
+    //one permit per accepted job that has not reached a terminal status
+    admission: Arc<Semaphore>,
+    max_pending_jobs: usize,
+    retry_after: Duration,
+    //accepted jobs go through the database instead of file_generator_sender when set
+    queue: Option<Arc<JobQueue>>,
+}
+
+impl JobRegistry {
+    pub fn new(
+        file_generator_sender: PrioritySender<FileGenerator>,
+        witness_generator_sender: PrioritySender<WitnessGenerator>,
+        proof_generator_sender: PrioritySender<ProofGenerator>,
+        max_pending_jobs: usize,
+        retry_after: Duration,
+        queue: Option<Arc<JobQueue>>,
+    ) -> Self {
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            file_generator_sender,
+            witness_generator_sender,
+            proof_generator_sender,
+            admission: Arc::new(Semaphore::new(max_pending_jobs)),
+            max_pending_jobs,
+            retry_after,
+            queue,
+        }
+    }
+
+    //jobs resumed after a restart are counted against the limit when there is room
+    pub fn register(
+        &self,
+        uuid: uuid::Uuid,
+        request_key: Option<Zeroizing<[u8; 32]>>,
+        result_key: Option<Zeroizing<[u8; 32]>>,
+    ) {
+        let admission = Arc::clone(&self.admission).try_acquire_owned().ok();
+        self.insert(uuid, request_key, result_key, admission);
+    }
+
+    fn insert(
+        &self,
+        uuid: uuid::Uuid,
+        request_key: Option<Zeroizing<[u8; 32]>>,
+        result_key: Option<Zeroizing<[u8; 32]>>,
+        admission: Option<OwnedSemaphorePermit>,
+    ) {
+        self.jobs.lock().unwrap().insert(
+            uuid,
+            Job {
+                request_key,
+                result_key,
+                cancellation: CancellationToken::new(),
+                _admission: admission,
+                leased: false,
+            },
+        );
+    }
+
+    //a job claimed from the database job queue, it may have been accepted by this instance
+    pub fn claim(&self, uuid: uuid::Uuid, result_key: Option<Zeroizing<[u8; 32]>>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.entry(uuid).or_insert_with(|| Job {
+            request_key: None,
+            result_key: None,
+            cancellation: CancellationToken::new(),
+            _admission: None,
+            leased: false,
+        });
+        job.result_key = result_key;
+        job.leased = true;
+    }
+
+    //jobs claimed from the database job queue that are still being worked on
+    pub fn leased(&self) -> Vec<uuid::Uuid> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, job)| job.leased)
+            .map(|(uuid, _)| *uuid)
+            .collect()
+    }
+
+    //admission control, taken before anything is written for the request so that a
+    //saturated server rejects it instead of queueing it behind every other job
+    pub fn reserve(&self) -> Result<OwnedSemaphorePermit, QueueFull> {
+        Arc::clone(&self.admission)
+            .try_acquire_owned()
+            .map_err(|_| QueueFull {
+                retry_after: self.retry_after,
+            })
+    }
+
+    pub async fn submit(
+        &self,
+        admission: OwnedSemaphorePermit,
+        file_generator: FileGenerator,
+        keys: &SessionKeys,
+        encrypt_result: bool,
+    ) -> Result<(), String> {
+        let uuid = file_generator.uuid();
+        let result_key = encrypt_result.then(|| keys.response_key.clone());
+
+        let Some(queue) = &self.queue else {
+            self.insert(
+                uuid,
+                Some(keys.request_key.clone()),
+                result_key,
+                Some(admission),
+            );
+            self.file_generator_sender.send(file_generator).await;
+            return Ok(());
+        };
+
+        //kept until the job reaches a terminal status on whichever instance claims it,
+        //so that the client can still cancel it here
+        self.insert(uuid, Some(keys.request_key.clone()), None, Some(admission));
+        let result = queue
+            .enqueue(
+                uuid,
+                &file_generator.proof_type(),
+                &file_generator.circuit,
+                result_key.as_deref(),
+            )
+            .await;
+        if result.is_err() {
+            self.finish(&uuid);
+        }
+        result
+    }
+
+    //forgets the jobs accepted here and finished by another instance, their status
+    //updates are the only sign of it
+    pub async fn forget_finished(
+        &self,
+        store: Arc<dyn ProofStore>,
+        mut status_updates: broadcast::Receiver<StatusUpdate>,
+    ) {
+        loop {
+            let proof_status = match status_updates.recv().await {
+                Ok(StatusUpdate::Status(proof_status)) => proof_status,
+                Ok(StatusUpdate::Resync) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                    self.forget_missed(&*store).await;
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            if !proof_status.status.is_terminal() {
+                continue;
+            }
+            self.forget(proof_status.request_id);
+        }
+    }
+
+    //looks up the jobs whose terminal status update may have been missed
+    async fn forget_missed(&self, store: &dyn ProofStore) {
+        let uuids: Vec<uuid::Uuid> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, job)| !job.leased)
+            .map(|(uuid, _)| *uuid)
+            .collect();
+        for uuid in uuids {
+            match store.get_proof_status(uuid).await {
+                Ok(Some(proof_status)) if !proof_status.status.is_terminal() => (),
+                //purged rows are finished as well
+                Ok(_) => self.forget(uuid),
+                Err(e) => {
+                    dbg!(&e);
+                }
+            }
+        }
+    }
+
+    fn forget(&self, uuid: uuid::Uuid) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.get(&uuid).is_some_and(|job| !job.leased) {
+            jobs.remove(&uuid);
+        }
+    }
+
+    pub fn queue_depths(&self) -> QueueDepths {
+        QueueDepths {
+            pending_jobs: QueueDepth {
+                len: self.max_pending_jobs - self.admission.available_permits(),
+                capacity: self.max_pending_jobs,
+            },
+            file_generation: QueueDepth::of(&self.file_generator_sender),
+            witness_generation: QueueDepth::of(&self.witness_generator_sender),
+            proof_generation: QueueDepth::of(&self.proof_generator_sender),
+        }
+    }
+
+    pub fn request_key(&self, uuid: &uuid::Uuid) -> Option<Zeroizing<[u8; 32]>> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(uuid)
+            .and_then(|job| job.request_key.clone())
+    }
+
+    pub fn result_key(&self, uuid: &uuid::Uuid) -> Option<Zeroizing<[u8; 32]>> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(uuid)
+            .and_then(|job| job.result_key.clone())
+    }
+
+    //jobs that are not registered can never be cancelled
+    pub fn cancellation(&self, uuid: &uuid::Uuid) -> CancellationToken {
+        match self.jobs.lock().unwrap().get(uuid) {
+            Some(job) => job.cancellation.clone(),
+            None => CancellationToken::new(),
+        }
+    }
+
+    //stops the job wherever it is, a queued job is withdrawn and a running
+    //witness or proof generation is killed by the stage holding it
+    pub async fn cancel(&self, uuid: uuid::Uuid) {
+        if let Some(job) = self.jobs.lock().unwrap().get(&uuid) {
+            job.cancellation.cancel();
+        }
+
+        let withdrawn = self.file_generator_sender.remove(uuid).is_some()
+            || self.witness_generator_sender.remove(uuid).is_some()
+            || self.proof_generator_sender.remove(uuid).is_some();
+        if withdrawn {
+            self.discard(uuid).await;
+        }
+    }
+
+    pub fn finish(&self, uuid: &uuid::Uuid) {
+        let job = self.jobs.lock().unwrap().remove(uuid);
+        //the row leaves the queue whatever the outcome, the proofs row holds it
+        if let (Some(job), Some(queue)) = (job, &self.queue) {
+            if job.leased {
+                let queue = Arc::clone(queue);
+                let uuid = *uuid;
+                tokio::spawn(async move {
+                    if let Err(e) = queue.complete(uuid).await {
+                        dbg!(&e);
+                    }
+                });
+            }
+        }
+    }
+
+    //drops a cancelled job together with its tmp folder
+    pub async fn discard(&self, uuid: uuid::Uuid) {
+        self.finish(&uuid);
+        remove_tmp_folder(&uuid).await;
+    }
+}
@@ -0,0 +1,145 @@
+use hkdf::Hkdf;
+use serde::Serialize;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+//negotiated in hello, v0 is the original scheme that uses the raw ECDH secret as the key
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(into = "u8")]
+pub enum ProtocolVersion {
+    V0,
+    V1,
+}
+
+impl From<ProtocolVersion> for u8 {
+    fn from(version: ProtocolVersion) -> u8 {
+        match version {
+            ProtocolVersion::V0 => 0,
+            ProtocolVersion::V1 => 1,
+        }
+    }
+}
+
+impl TryFrom<u8> for ProtocolVersion {
+    type Error = String;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ProtocolVersion::V0),
+            1 => Ok(ProtocolVersion::V1),
+            _ => Err(format!("Unsupported protocol version: {}", value)),
+        }
+    }
+}
+
+//keys of one hello session, one per direction
+#[derive(Clone)]
+pub struct SessionKeys {
+    //decrypts what the client sends: submit_request and cancel
+    pub request_key: Zeroizing<[u8; 32]>,
+    //seals what the server returns: fetch_result
+    pub response_key: Zeroizing<[u8; 32]>,
+}
+
+impl SessionKeys {
+    pub fn derive(
+        version: ProtocolVersion,
+        shared_secret: &[u8; 32],
+        uuid: &uuid::Uuid,
+        client_public_key: &[u8],
+        server_public_key: &[u8],
+    ) -> Self {
+        match version {
+            ProtocolVersion::V0 => SessionKeys {
+                request_key: Zeroizing::new(*shared_secret),
+                response_key: expand(
+                    &Hkdf::<Sha256>::new(Some(uuid.as_bytes()), shared_secret),
+                    b"openpassport result",
+                ),
+            },
+            //the salt binds the keys to the request and to both attested public keys
+            ProtocolVersion::V1 => {
+                let salt = [uuid.as_bytes(), client_public_key, server_public_key].concat();
+                let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared_secret);
+                SessionKeys {
+                    request_key: expand(&hkdf, b"openpassport v1 client to server"),
+                    response_key: expand(&hkdf, b"openpassport v1 server to client"),
+                }
+            }
+        }
+    }
+}
+
+fn expand(hkdf: &Hkdf<Sha256>, info: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    hkdf.expand(info, key.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> [u8; 32] {
+        let bytes: Vec<u8> = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect();
+        bytes.try_into().unwrap()
+    }
+
+    //fixed inputs, the expected keys were computed with an independent HKDF-SHA256
+    const SHARED_SECRET: [u8; 32] = [0x11; 32];
+    const UUID: &str = "00112233-4455-6677-8899-aabbccddeeff";
+
+    fn public_key(prefix: u8, fill: u8) -> Vec<u8> {
+        [[prefix].as_slice(), &[fill; 32]].concat()
+    }
+
+    fn derive(version: ProtocolVersion, client_public_key: &[u8]) -> SessionKeys {
+        SessionKeys::derive(
+            version,
+            &SHARED_SECRET,
+            &uuid::Uuid::parse_str(UUID).unwrap(),
+            client_public_key,
+            &public_key(0x03, 0x33),
+        )
+    }
+
+    #[test]
+    fn v0_keeps_the_ecdh_secret_as_request_key() {
+        let keys = derive(ProtocolVersion::V0, &public_key(0x02, 0x22));
+        assert_eq!(*keys.request_key, SHARED_SECRET);
+        assert_eq!(
+            *keys.response_key,
+            hex("8c6acc9283aba9a7b40028f4f6b592aac5942b245c6502408e8657fd1d99775e")
+        );
+    }
+
+    #[test]
+    fn v1_derives_a_key_per_direction() {
+        let keys = derive(ProtocolVersion::V1, &public_key(0x02, 0x22));
+        assert_eq!(
+            *keys.request_key,
+            hex("295d11aa540e52c39c47a067b0ab03f4916d4ec4231dc3524845167d1f505dd4")
+        );
+        assert_eq!(
+            *keys.response_key,
+            hex("0473dfcbad33fb9a7b75fae2e54fef0faa94f70e3b20f22e35488f227e76fb87")
+        );
+    }
+
+    #[test]
+    fn v1_keys_are_bound_to_the_public_keys() {
+        let keys = derive(ProtocolVersion::V1, &public_key(0x02, 0x22));
+        let other = derive(ProtocolVersion::V1, &public_key(0x02, 0x23));
+        assert_ne!(*keys.request_key, *other.request_key);
+        assert_ne!(*keys.response_key, *other.response_key);
+    }
+
+    #[test]
+    fn unknown_version_is_rejected() {
+        assert_eq!(ProtocolVersion::try_from(1), Ok(ProtocolVersion::V1));
+        assert!(ProtocolVersion::try_from(2).is_err());
+    }
+}
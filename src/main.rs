@@ -1,71 +1,134 @@
 mod args;
+mod attestation;
 mod db;
 mod generator;
+mod hpke;
+mod job_queue;
+mod jobs;
+mod key_schedule;
+mod rate_limit;
+mod recovery;
+mod retention;
+mod retry;
+mod rpc_error;
+mod scheduler;
 mod server;
 mod store;
 mod types;
 mod utils;
+mod worker_pool;
 
 use std::collections::HashMap;
 use std::path;
 use std::sync::Arc;
 
 use clap::Parser;
-use db::{set_witness_generated, update_proof};
-use generator::{proof_generator::ProofGenerator, witness_generator::WitnessGenerator};
-use google_cloud_secretmanager_v1::client::SecretManagerService;
-use jsonrpsee::server::Server;
-use server::RpcServer;
-use sqlx::postgres::PgPoolOptions;
-use utils::{cleanup, get_tmp_folder_path};
+use db::source::DatabaseUrlSource;
+use db::types::FailureStage;
+use db::{update_proof, Failure};
+use generator::{
+    proof_generator::ProofGenerator, witness_generator::WitnessGenerator, GeneratorError,
+    StageTimeouts,
+};
+use db::queue::JobQueue;
+use job_queue::JobQueueKind;
+use jobs::JobRegistry;
+use scheduler::{PriorityPolicy, Prioritized};
+use server::{AdminRpcServer, RpcServer};
+use utils::{cleanup, remove_tmp_folder};
+use worker_pool::{CircuitCost, WorkerPool};
 
 #[tokio::main]
 async fn main() {
-    let client = SecretManagerService::builder().build().await.unwrap();
+    let config = args::Config::parse();
 
-    let project = std::env::var("PROJECT_ID").unwrap();
-    let secret = std::env::var("SECRET_ID").unwrap();
-    let name = format!("projects/{}/secrets/{}/versions/latest", project, secret);
+    if config.dev {
+        println!("Running in development mode, attestations are not produced by a TEE");
+    }
 
-    let resp = client
-        .access_secret_version()
-        .set_name(name)
-        .send()
-        .await
-        .unwrap();
+    let database_url = match DatabaseUrlSource::from_config(&config) {
+        Ok(source) => source.resolve().await,
+        Err(e) => Err(e),
+    };
+    let database_url = match database_url {
+        Ok(database_url) => database_url,
+        Err(e) => {
+            panic!("Could not get the database url: {}", e);
+        }
+    };
 
-    let payload = resp.payload.unwrap().data.to_vec();
-    //creds is a plain string with the db url
-    let database_url = String::from_utf8(payload).unwrap();
+    if let Some(args::Command::Migrate) = config.command {
+        let store = match db::connect(&database_url, config.dev).await {
+            Ok(store) => store,
+            Err(e) => {
+                panic!("Error: {:?}", e);
+            }
+        };
+        match store.migrate().await {
+            Ok(applied) => println!("Applied {} migrations", applied),
+            Err(e) => panic!("Could not migrate the database: {}", e),
+        }
+        return;
+    }
 
-    let config = args::Config::parse();
-    let server_url = config.server_address;
+    let attestation_provider = match config.attestation_provider_kind() {
+        Ok(kind) => kind.build(),
+        Err(e) => {
+            panic!("Invalid attestation provider: {}", e);
+        }
+    };
+    let instance_id = match config.instance_id() {
+        Ok(instance_id) => instance_id,
+        Err(e) => {
+            panic!("Invalid instance id: {}", e);
+        }
+    };
 
-    let server = Server::builder().build(server_url).await.unwrap();
+    let memory_budget = config.memory_budget();
+    let witness_pool = WorkerPool::new(config.witness_workers(), memory_budget.clone());
+    let prover_pool = WorkerPool::new(config.prover_workers.max(1), memory_budget);
 
-    let (file_generator_sender, mut file_generator_receiver) = tokio::sync::mpsc::channel(10);
-    let (witness_generator_sender, mut witness_generator_receiver) = tokio::sync::mpsc::channel(10);
-    let (proof_generator_sender, mut proof_generator_receiver) = tokio::sync::mpsc::channel(10);
+    let priority_weights = config.priority_weight.iter().cloned().collect();
+    let priority_aging = std::time::Duration::from_secs(config.priority_aging_secs);
+
+    let witness_timeouts =
+        StageTimeouts::new(config.witness_timeout_secs, &config.circuit_witness_timeout);
+    let proof_timeouts = StageTimeouts::new(config.proof_timeout_secs, &config.circuit_proof_timeout);
+    let retry_policy = config.retry_policy();
+    let rate_limiter = Arc::new(config.rate_limiter());
+    let job_queue_capacity = config.job_queue_capacity();
+    let retention_max_age = config.retention_max_age();
+    let server_url = config.server_address;
 
-    let server_addr = server.local_addr().unwrap();
+    let listener = tokio::net::TcpListener::bind(server_url).await.unwrap();
+
+    let server_addr = listener.local_addr().unwrap();
 
     println!("Server running on: http://{}", server_addr);
 
-    let pool = match PgPoolOptions::new()
-        .max_connections(20)
-        .connect(&database_url)
-        .await
-    {
-        Ok(pool) => pool,
+    let store = match db::connect(&database_url, config.dev).await {
+        Ok(store) => store,
         Err(e) => {
             panic!("Error: {:?}", e);
         }
     };
 
+    let schema = if config.migrate {
+        store.migrate().await.map(|applied| {
+            println!("Applied {} migrations", applied);
+        })
+    } else {
+        store.check_schema().await
+    };
+    if let Err(e) = schema {
+        panic!("{}", e);
+    }
+
     let circuit_folder = config.circuit_folder;
     let zkey_folder = config.zkey_folder;
 
     let mut circuit_zkey_map = HashMap::new();
+    let mut circuit_cost_map = HashMap::new();
 
     let entries = std::fs::read_dir(std::path::Path::new(&circuit_folder)).unwrap();
 
@@ -83,21 +146,160 @@ async fn main() {
             panic!("zkey {zkey_path_str} does not exist!");
         }
 
+        circuit_cost_map.insert(
+            circuit_name.clone(),
+            CircuitCost::estimate(path::Path::new(&circuit_folder), &circuit_name, &zkey_path),
+        );
         circuit_zkey_map.insert(circuit_name, zkey_path_str.to_string());
     }
 
     let circuit_zkey_map_arc = Arc::new(circuit_zkey_map);
 
-    let handle = server.start(
+    let priority_policy = Arc::new(PriorityPolicy::new(
+        priority_weights,
+        circuit_cost_map
+            .iter()
+            .map(|(circuit_name, cost)| (circuit_name.clone(), cost.prover_mb))
+            .collect(),
+        priority_aging,
+    ));
+    let (file_generator_sender, mut file_generator_receiver) =
+        scheduler::channel(10, Arc::clone(&priority_policy));
+    let (witness_generator_sender, mut witness_generator_receiver) =
+        scheduler::channel(10, Arc::clone(&priority_policy));
+    let (proof_generator_sender, mut proof_generator_receiver) =
+        scheduler::channel(10, priority_policy);
+
+    let (status_update_sender, _) = tokio::sync::broadcast::channel(1024);
+
+    let job_queue = match config.job_queue {
+        JobQueueKind::Local => None,
+        JobQueueKind::Database => {
+            let key = match config.job_queue_key.as_deref().map(JobQueue::parse_key) {
+                Some(Ok(key)) => key,
+                Some(Err(e)) => panic!("{}", e),
+                None => panic!("--job-queue database needs --job-queue-key"),
+            };
+            let lease = std::time::Duration::from_secs(config.job_lease_secs.max(1));
+            match JobQueue::connect(&database_url, instance_id.clone(), lease, key).await {
+                Ok(job_queue) => Some(Arc::new(job_queue)),
+                Err(e) => panic!("Could not connect the job queue: {}", e),
+            }
+        }
+    };
+
+    let jobs = Arc::new(JobRegistry::new(
+        file_generator_sender.clone(),
+        witness_generator_sender.clone(),
+        proof_generator_sender.clone(),
+        config.max_pending_jobs.max(1),
+        std::time::Duration::from_secs(config.queue_full_retry_after_secs),
+        job_queue.clone(),
+    ));
+
+    let session_ttl = std::time::Duration::from_secs(config.session_ttl_secs);
+    let session_store = Arc::new(store::LruStore::new(
+        config.session_capacity.max(1),
+        session_ttl,
+    ));
+    Arc::clone(&session_store)
+        .spawn_sweeper((session_ttl / 2).max(std::time::Duration::from_secs(1)));
+
+    let hpke_keys = Arc::new(hpke::HpkeKeyRing::new(std::time::Duration::from_secs(
+        config.hpke_rotation_secs.max(1),
+    )));
+    Arc::clone(&hpke_keys).spawn_rotation();
+
+    let retention = Arc::new(retention::Retention::new(
+        Arc::clone(&store),
+        retention_max_age,
+        config.retention_mode,
+    ));
+    Arc::clone(&retention).spawn_purger(std::time::Duration::from_secs(
+        config.retention_interval_secs.max(1),
+    ));
+
+    //the jobs to recover are read before the server starts, recovery itself needs the
+    //pipeline below to drain the queues
+    let unfinished = match job_queue {
+        None => recovery::unfinished_jobs(&instance_id, &*store).await,
+        Some(_) => Vec::new(),
+    };
+
+    let (admin_handle, admin_addr) = match server::start_admin(
+        &config.admin_address,
+        server::AdminRpcServerImpl::new(Arc::clone(&jobs), Arc::clone(&rate_limiter), retention)
+            .into_rpc(),
+    )
+    .await
+    {
+        Ok(admin) => admin,
+        Err(e) => panic!("Could not start the admin server: {}", e),
+    };
+    println!("Admin server running on: http://{}", admin_addr);
+
+    let handle = server::start(
+        listener,
         server::RpcServerImpl::new(
-            store::LruStore::new(1000),
-            file_generator_sender,
+            session_store,
+            Arc::clone(&jobs),
             Arc::clone(&circuit_zkey_map_arc),
-            pool.clone(),
+            Arc::clone(&store),
+            status_update_sender.clone(),
+            attestation_provider,
+            hpke_keys,
+            instance_id.clone(),
         )
         .into_rpc(),
+        Arc::clone(&rate_limiter),
+        config.trust_forwarded_for,
     );
 
+    //jobs of the database queue are claimed again once their lease runs out, the
+    //local queue only has the tmp folders of this instance to go on
+    match job_queue {
+        None => {
+            tokio::spawn({
+                let store = Arc::clone(&store);
+                let circuit_zkey_map_arc = Arc::clone(&circuit_zkey_map_arc);
+                let jobs = Arc::clone(&jobs);
+                let witness_generator_sender = witness_generator_sender.clone();
+                let proof_generator_sender = proof_generator_sender.clone();
+                async move {
+                    recovery::recover_jobs(
+                        unfinished,
+                        &*store,
+                        circuit_zkey_map_arc,
+                        jobs,
+                        witness_generator_sender,
+                        proof_generator_sender,
+                    )
+                    .await;
+                }
+            });
+        }
+        Some(job_queue) => {
+            tokio::spawn(job_queue::claim_jobs(
+                Arc::clone(&job_queue),
+                Arc::clone(&store),
+                Arc::clone(&circuit_zkey_map_arc),
+                Arc::clone(&jobs),
+                file_generator_sender.clone(),
+                proof_generator_sender.clone(),
+                job_queue_capacity,
+                config.job_max_claims,
+                std::time::Duration::from_millis(config.job_queue_poll_ms),
+            ));
+            tokio::spawn(job_queue::renew_leases(job_queue, Arc::clone(&jobs)));
+            tokio::spawn({
+                let jobs = Arc::clone(&jobs);
+                let store = Arc::clone(&store);
+                let status_updates = status_update_sender.subscribe();
+                async move { jobs.forget_finished(store, status_updates).await }
+            });
+        }
+    }
+
     let rapid_snark_path_exe = path::Path::new(&config.rapidsnark_path)
         .join("package")
         .join("bin")
@@ -113,71 +315,116 @@ async fn main() {
             println!("Server stopped");
         }
 
+        _ = admin_handle.stopped() => {
+            println!("Admin server stopped");
+        }
+
+    result = store.listen_status_updates(status_update_sender) => {
+        if let Err(e) = result {
+            panic!("Could not listen to status updates: {:?}", e);
+        }
+    }
+
     _ = async {
-        while let Some(file_generator) = file_generator_receiver.recv().await {
+        loop {
+            let file_generator = file_generator_receiver.recv().await;
             let uuid = file_generator.uuid();
+            let proof_type = file_generator.proof_type();
+            let cancellation = jobs.cancellation(&uuid);
 
-            let pool_clone = pool.clone();
+            let store_clone = Arc::clone(&store);
+            let jobs = Arc::clone(&jobs);
             let witness_generator_clone = witness_generator_sender.clone();
             tokio::spawn(async move {
-                let (uuid, circuit_name) = match file_generator.run().await {
+                if cancellation.is_cancelled() {
+                    jobs.discard(uuid).await;
+                    return;
+                }
+                let result = retry_policy
+                    .run(uuid, &*store_clone, || file_generator.run())
+                    .await;
+                let (uuid, circuit_name) = match result {
                     Ok((uuid, circuit_name)) => (uuid, circuit_name),
                     Err(e) => {
                         dbg!(&e);
-                        cleanup(uuid.clone(), &pool_clone, e.to_string()).await;
+                        let failure = Failure::new(Some(FailureStage::File), Some("io"), e.to_string());
+                        cleanup(uuid, &*store_clone, failure).await;
+                        jobs.finish(&uuid);
                         return;
                     }
                 };
-                if let Err(e) = witness_generator_clone.send(WitnessGenerator::new(
-                    uuid.clone(),
+                witness_generator_clone.send(WitnessGenerator::new(
+                    uuid,
+                    proof_type,
                     circuit_name
-                )).await {
-                    dbg!(&e);
-                    cleanup(uuid, &pool_clone, e.to_string()).await;
-                    return;
-                }
+                )).await;
             });
         }
     } => {}
 
     _ = async {
-        while let Some(witness_generator) = witness_generator_receiver.recv().await {
+        loop {
+            let mut permit = witness_pool.acquire_worker().await;
+            let witness_generator = witness_generator_receiver.recv().await;
+            let uuid = witness_generator.uuid;
+            let cancellation = jobs.cancellation(&uuid);
+            if cancellation.is_cancelled() {
+                jobs.discard(uuid).await;
+                continue;
+            }
+            let memory_mb = circuit_cost_map
+                .get(witness_generator.circuit_name())
+                .map(|cost| cost.witness_mb)
+                .unwrap_or(0);
+            witness_pool.reserve_memory(&mut permit, memory_mb).await;
+
             let circuit_zkey_map_arc_clone = Arc::clone(&circuit_zkey_map_arc);
             let proof_generator_sender_clone = proof_generator_sender.clone();
 
             let circuit_folder = circuit_folder.clone();
-            let zkey_folder = zkey_folder.clone();
 
-            let uuid = witness_generator.uuid.clone();
+            let proof_type = witness_generator.proof_type();
 
-            let pool_clone = pool.clone();
+            let timeout = witness_timeouts.for_circuit(witness_generator.circuit_name());
+
+            let store_clone = Arc::clone(&store);
+            let jobs = Arc::clone(&jobs);
             tokio::spawn(async move {
-                match witness_generator
-                    .run(&circuit_folder)
-                    .await {
+                let result = retry_policy
+                    .run(uuid, &*store_clone, || {
+                        witness_generator.run(&circuit_folder, timeout, &cancellation)
+                    })
+                    .await;
+                //release the worker before waiting on the next stage
+                drop(permit);
+
+                match result {
                     Ok((uuid, circuit_name)) => {
-                        let zkey_file = circuit_zkey_map_arc_clone.get(circuit_name.as_str()).unwrap();
-                        let zkey_file_path = path::Path::new(&zkey_folder).join(zkey_file).to_str().unwrap().to_string();
+                        //the map already holds the full path of the zkey
+                        let zkey_file_path = circuit_zkey_map_arc_clone.get(circuit_name.as_str()).unwrap().clone();
 
-                        if let Err(e) = set_witness_generated(uuid.clone(), &pool_clone).await {
+                        let result = retry_policy
+                            .run(uuid, &*store_clone, || store_clone.set_witness_generated(uuid))
+                            .await;
+                        if let Err(e) = result {
                             dbg!(&e);
-                            cleanup(uuid.clone(), &pool_clone, e.to_string()).await;
+                            cleanup(uuid, &*store_clone, Failure::db(&e)).await;
+                            jobs.finish(&uuid);
                             return;
                         }
 
-                        if let Err(e) = proof_generator_sender_clone.send(ProofGenerator::new(
-                            uuid.clone(),
+                        proof_generator_sender_clone.send(ProofGenerator::new(
+                            uuid,
+                            proof_type,
+                            circuit_name,
                             zkey_file_path,
-                        )).await {
-                            dbg!(&e);
-                            cleanup(uuid.clone(), &pool_clone, e.to_string()).await;
-                            return;
-                        }
+                        )).await;
                     },
+                    Err(GeneratorError::Cancelled) => jobs.discard(uuid).await,
                     Err(e) => {
                         dbg!(&e);
-                        cleanup(uuid.clone(), &pool_clone, e.to_string()).await;
-                        return;
+                        cleanup(uuid, &*store_clone, e.failure(FailureStage::Witness)).await;
+                        jobs.finish(&uuid);
                     }
                 }
             });
@@ -185,21 +432,59 @@ async fn main() {
     } => {}
 
     _ = async {
-        while let Some(proof_generator) = proof_generator_receiver.recv().await {
+        loop {
+            let mut permit = prover_pool.acquire_worker().await;
+            let proof_generator = proof_generator_receiver.recv().await;
             let uuid = proof_generator.uuid();
-
-            if let Err(e) = proof_generator.run(&rapid_snark_path).await {
-                dbg!(&e);
-                cleanup(uuid.clone(), &pool, e.to_string()).await;
+            let cancellation = jobs.cancellation(&uuid);
+            if cancellation.is_cancelled() {
+                jobs.discard(uuid).await;
                 continue;
             }
-            if let Err(e) = update_proof(uuid.clone(), &pool).await {
-                dbg!(&e);
-                cleanup(uuid.clone(), &pool, e.to_string()).await;
-                continue;
-            }
-            let tmp_folder = get_tmp_folder_path(&uuid.to_string());
-            let _ = tokio::fs::remove_dir_all(tmp_folder).await;
+            let memory_mb = circuit_cost_map
+                .get(proof_generator.circuit_name())
+                .map(|cost| cost.prover_mb)
+                .unwrap_or(0);
+            prover_pool.reserve_memory(&mut permit, memory_mb).await;
+
+            let timeout = proof_timeouts.for_circuit(proof_generator.circuit_name());
+            let store_clone = Arc::clone(&store);
+            let jobs = Arc::clone(&jobs);
+            let rapid_snark_path = rapid_snark_path.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+
+                let result = retry_policy
+                    .run(uuid, &*store_clone, || {
+                        proof_generator.run(&rapid_snark_path, timeout, &cancellation)
+                    })
+                    .await;
+                match result {
+                    Ok(()) => (),
+                    Err(GeneratorError::Cancelled) => {
+                        jobs.discard(uuid).await;
+                        return;
+                    }
+                    Err(e) => {
+                        dbg!(&e);
+                        cleanup(uuid, &*store_clone, e.failure(FailureStage::Proof)).await;
+                        jobs.finish(&uuid);
+                        return;
+                    }
+                }
+                let result_key = jobs.result_key(&uuid);
+                let result = retry_policy
+                    .run(uuid, &*store_clone, || update_proof(uuid, &*store_clone, result_key.as_deref()))
+                    .await;
+                if let Err(e) = result {
+                    dbg!(&e);
+                    cleanup(uuid, &*store_clone, e.failure()).await;
+                    jobs.finish(&uuid);
+                    return;
+                }
+                jobs.finish(&uuid);
+                remove_tmp_folder(&uuid).await;
+            });
         }
     } => {}
     }
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use jsonrpsee::server::middleware::rpc::RpcServiceT;
+use jsonrpsee::server::HttpRequest;
+use jsonrpsee::types::{ErrorObjectOwned, Request};
+use jsonrpsee::MethodResponse;
+use lru::LruCache;
+
+use crate::rpc_error::RpcError;
+use crate::types::RateLimitStats;
+
+//methods that cost an attestation, a key agreement or a job, each gets its own
+//bucket per client on top of the bucket shared by every method
+pub const EXPENSIVE_METHODS: [&str; 4] = [
+    "openpassport_hello",
+    "openpassport_submit_request",
+    "openpassport_submit_sealed",
+    "openpassport_attestation",
+];
+
+//the address a request is accounted to, put in the request extensions by the server
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    //the peer address, or the last X-Forwarded-For entry when the server sits behind a
+    //proxy that appends it, earlier entries are set by the client and can be forged
+    pub fn of<B>(request: &HttpRequest<B>, peer: IpAddr, trust_forwarded_for: bool) -> Self {
+        let forwarded = trust_forwarded_for
+            .then(|| {
+                request
+                    .headers()
+                    .get_all("x-forwarded-for")
+                    .iter()
+                    .next_back()
+            })
+            .flatten()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok());
+        ClientIp(forwarded.unwrap_or(peer))
+    }
+
+    //an IPv6 client usually holds a whole /64, so it is limited as one
+    fn key(&self) -> IpAddr {
+        match self.0 {
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => IpAddr::V4(ip),
+                None => {
+                    let [a, b, c, d, ..] = ip.segments();
+                    IpAddr::V6(Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0))
+                }
+            },
+            ip => ip,
+        }
+    }
+}
+
+//a client sent more requests than its limits allow, it should come back after retry_after
+pub struct RateLimited {
+    pub retry_after: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    per_second: f64,
+    burst: f64,
+}
+
+impl Limit {
+    //None when per_minute is 0, which disables the limit
+    pub fn per_minute(per_minute: u32, burst: u32) -> Option<Self> {
+        (per_minute > 0).then(|| Limit {
+            per_second: per_minute as f64 / 60.0,
+            burst: burst.max(1) as f64,
+        })
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: &Limit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: limit.burst,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst);
+        self.updated_at = now;
+    }
+
+    //time until the bucket holds a whole token again
+    fn wait(&self, limit: &Limit) -> Option<Duration> {
+        (self.tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - self.tokens) / limit.per_second))
+    }
+}
+
+struct ClientBuckets {
+    //None when the per ip limit is disabled
+    all: Option<TokenBucket>,
+    methods: HashMap<&'static str, TokenBucket>,
+}
+
+pub struct RateLimiter {
+    //the least recently seen clients are forgotten first, which only resets their buckets
+    clients: Mutex<LruCache<IpAddr, ClientBuckets>>,
+    per_ip: Option<Limit>,
+    per_method: Option<Limit>,
+    rejected_per_ip: AtomicU64,
+    rejected_per_method: HashMap<&'static str, AtomicU64>,
+}
+
+impl RateLimiter {
+    pub fn new(per_ip: Option<Limit>, per_method: Option<Limit>, max_clients: usize) -> Self {
+        Self {
+            clients: Mutex::new(LruCache::new(
+                NonZeroUsize::new(max_clients.max(1)).unwrap(),
+            )),
+            per_ip,
+            per_method,
+            rejected_per_ip: AtomicU64::new(0),
+            rejected_per_method: EXPENSIVE_METHODS
+                .iter()
+                .map(|method| (*method, AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+
+    //takes a token from every bucket the call draws from, or none of them when one is empty
+    pub fn check(&self, client_ip: ClientIp, method_name: &str) -> Result<(), RateLimited> {
+        self.check_at(client_ip, method_name, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        client_ip: ClientIp,
+        method_name: &str,
+        now: Instant,
+    ) -> Result<(), RateLimited> {
+        let method = EXPENSIVE_METHODS
+            .iter()
+            .find(|method| **method == method_name)
+            .copied();
+        let method_limit = method.and(self.per_method);
+        if self.per_ip.is_none() && method_limit.is_none() {
+            return Ok(());
+        }
+
+        let mut clients = self.clients.lock().unwrap();
+        let buckets = clients.get_or_insert_mut(client_ip.key(), || ClientBuckets {
+            all: self.per_ip.map(|limit| TokenBucket::full(&limit, now)),
+            methods: HashMap::new(),
+        });
+
+        if let (Some(limit), Some(bucket)) = (&self.per_ip, &mut buckets.all) {
+            bucket.refill(limit, now);
+            if let Some(retry_after) = bucket.wait(limit) {
+                self.rejected_per_ip.fetch_add(1, Ordering::Relaxed);
+                return Err(RateLimited { retry_after });
+            }
+        }
+        if let (Some(method), Some(limit)) = (method, &method_limit) {
+            let bucket = buckets
+                .methods
+                .entry(method)
+                .or_insert_with(|| TokenBucket::full(limit, now));
+            bucket.refill(limit, now);
+            if let Some(retry_after) = bucket.wait(limit) {
+                self.rejected_per_method[method].fetch_add(1, Ordering::Relaxed);
+                return Err(RateLimited { retry_after });
+            }
+            bucket.tokens -= 1.0;
+        }
+        if let Some(bucket) = &mut buckets.all {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        RateLimitStats {
+            tracked_clients: self.clients.lock().unwrap().len(),
+            rejected_per_ip: self.rejected_per_ip.load(Ordering::Relaxed),
+            rejected_per_method: self
+                .rejected_per_method
+                .iter()
+                .map(|(method, count)| (method.to_string(), count.load(Ordering::Relaxed)))
+                .collect(),
+        }
+    }
+}
+
+//rpc middleware, calls of a client over its limits are answered with RateLimited
+//without reaching the method
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    service: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S> RateLimit<S> {
+    pub fn new(service: S, limiter: Arc<RateLimiter>) -> Self {
+        Self { service, limiter }
+    }
+}
+
+impl<'a, S> RpcServiceT<'a> for RateLimit<S>
+where
+    S: RpcServiceT<'a> + Send + Sync + Clone + 'static,
+    S::Future: 'a,
+{
+    type Future = Pin<Box<dyn Future<Output = MethodResponse> + Send + 'a>>;
+
+    fn call(&self, request: Request<'a>) -> Self::Future {
+        if let Some(client_ip) = request.extensions().get::<ClientIp>() {
+            if let Err(rate_limited) = self.limiter.check(*client_ip, request.method_name()) {
+                let error = ErrorObjectOwned::from(RpcError::from(rate_limited));
+                return Box::pin(std::future::ready(MethodResponse::error(
+                    request.id(),
+                    error,
+                )));
+            }
+        }
+        Box::pin(self.service.call(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const HELLO: &str = "openpassport_hello";
+    const STATUS: &str = "openpassport_status";
+
+    fn client(last: u8) -> ClientIp {
+        ClientIp(IpAddr::V4(Ipv4Addr::new(192, 0, 2, last)))
+    }
+
+    #[test]
+    fn burst_then_rejected_until_refilled() {
+        let limiter = RateLimiter::new(Limit::per_minute(60, 3), None, 16);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at(client(1), STATUS, now).is_ok());
+        }
+        let rejected = limiter.check_at(client(1), STATUS, now).err().unwrap();
+        assert_eq!(rejected.retry_after, Duration::from_secs(1));
+
+        //one token per second at 60 per minute
+        let later = now + Duration::from_millis(1500);
+        assert!(limiter.check_at(client(1), STATUS, later).is_ok());
+        assert!(limiter.check_at(client(1), STATUS, later).is_err());
+        //the bucket never holds more than the burst
+        let much_later = now + Duration::from_secs(3600);
+        for _ in 0..3 {
+            assert!(limiter.check_at(client(1), STATUS, much_later).is_ok());
+        }
+        assert!(limiter.check_at(client(1), STATUS, much_later).is_err());
+
+        //other clients have their own bucket
+        assert!(limiter.check_at(client(2), STATUS, now).is_ok());
+        assert_eq!(limiter.stats().rejected_per_ip, 3);
+    }
+
+    #[test]
+    fn expensive_methods_have_their_own_bucket() {
+        let limiter = RateLimiter::new(Limit::per_minute(600, 10), Limit::per_minute(6, 1), 16);
+        let now = Instant::now();
+        assert!(limiter.check_at(client(1), HELLO, now).is_ok());
+        assert!(limiter.check_at(client(1), HELLO, now).is_err());
+        assert!(limiter.check_at(client(1), STATUS, now).is_ok());
+
+        let stats = limiter.stats();
+        assert_eq!(stats.rejected_per_method[HELLO], 1);
+        assert_eq!(stats.rejected_per_ip, 0);
+    }
+
+    #[test]
+    fn rejected_call_takes_no_token() {
+        let limiter = RateLimiter::new(Limit::per_minute(60, 2), Limit::per_minute(6, 1), 16);
+        let now = Instant::now();
+        assert!(limiter.check_at(client(1), HELLO, now).is_ok());
+        //rejected by the hello bucket, the shared bucket keeps its last token
+        assert!(limiter.check_at(client(1), HELLO, now).is_err());
+        assert!(limiter.check_at(client(1), STATUS, now).is_ok());
+        assert!(limiter.check_at(client(1), STATUS, now).is_err());
+    }
+
+    #[test]
+    fn least_recent_client_is_evicted() {
+        let limiter = RateLimiter::new(Limit::per_minute(60, 1), None, 2);
+        let now = Instant::now();
+        assert!(limiter.check_at(client(1), STATUS, now).is_ok());
+        assert!(limiter.check_at(client(1), STATUS, now).is_err());
+        assert!(limiter.check_at(client(2), STATUS, now).is_ok());
+        assert!(limiter.check_at(client(3), STATUS, now).is_ok());
+        assert_eq!(limiter.stats().tracked_clients, 2);
+
+        //forgetting a client resets its bucket
+        assert!(limiter.check_at(client(1), STATUS, now).is_ok());
+        //client 2 was seen least recently and was pushed out in turn
+        assert!(limiter.check_at(client(3), STATUS, now).is_err());
+        assert!(limiter.check_at(client(2), STATUS, now).is_ok());
+    }
+
+    #[test]
+    fn disabled_limits_accept_everything() {
+        let limiter = RateLimiter::new(Limit::per_minute(0, 1), Limit::per_minute(0, 1), 16);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(limiter.check_at(client(1), HELLO, now).is_ok());
+        }
+        assert_eq!(limiter.stats().tracked_clients, 0);
+    }
+
+    #[test]
+    fn ipv6_clients_are_limited_per_64() {
+        let limiter = RateLimiter::new(Limit::per_minute(60, 1), None, 16);
+        let now = Instant::now();
+        let ip = |last: u16| {
+            ClientIp(IpAddr::V6(Ipv6Addr::new(
+                0x2001, 0xdb8, 1, 2, 0, 0, 0, last,
+            )))
+        };
+        assert!(limiter.check_at(ip(1), STATUS, now).is_ok());
+        assert!(limiter.check_at(ip(2), STATUS, now).is_err());
+    }
+}
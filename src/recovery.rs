@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::path;
+use std::sync::Arc;
+
+use crate::db::types::{Status, UnfinishedProof};
+use crate::db::{Failure, ProofStore};
+use crate::generator::{proof_generator::ProofGenerator, witness_generator::WitnessGenerator};
+use crate::jobs::JobRegistry;
+use crate::scheduler::PrioritySender;
+use crate::utils::{cleanup, get_tmp_folder_path};
+
+pub const RESTART_REASON: &str = "server restarted before the proof could be generated";
+const SEALED_RESTART_REASON: &str =
+    "server restarted, the session key to seal the result with is lost";
+
+//the jobs this instance was working on before a restart, must be read before the
+//server accepts requests or the new ones would be recovered as well
+pub async fn unfinished_jobs(instance_id: &str, store: &dyn ProofStore) -> Vec<UnfinishedProof> {
+    match store.get_unfinished_proofs(instance_id).await {
+        Ok(unfinished) => unfinished,
+        Err(e) => {
+            println!("Could not recover unfinished jobs: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+//re-enqueues the unfinished jobs at the stage their tmp folder allows, and fails the
+//ones that cannot be resumed
+pub async fn recover_jobs(
+    unfinished: Vec<UnfinishedProof>,
+    store: &dyn ProofStore,
+    circuit_zkey_map: Arc<HashMap<String, String>>,
+    jobs: Arc<JobRegistry>,
+    witness_generator_sender: PrioritySender<WitnessGenerator>,
+    proof_generator_sender: PrioritySender<ProofGenerator>,
+) {
+    let (mut resumed, mut failed) = (0, 0);
+    for unfinished_proof in unfinished {
+        let uuid = unfinished_proof.request_id;
+        let circuit_name = unfinished_proof.circuit_name;
+        let tmp_folder_path = get_tmp_folder_path(&uuid.to_string());
+        let tmp_folder = path::Path::new(&tmp_folder_path);
+
+        let Some(zkey_file_path) = circuit_zkey_map.get(&circuit_name) else {
+            let reason = format!("{}: unknown circuit", RESTART_REASON);
+            cleanup(uuid, store, Failure::new(None, Some("restart"), reason)).await;
+            failed += 1;
+            continue;
+        };
+
+        if unfinished_proof.encrypted_result {
+            let failure = Failure::new(None, Some("restart"), SEALED_RESTART_REASON);
+            cleanup(uuid, store, failure).await;
+            failed += 1;
+            continue;
+        }
+
+        jobs.register(uuid, None, None);
+        //the witness is only complete once the row says so, otherwise it is generated again
+        if unfinished_proof.status == Status::WitnessGenerated
+            && tmp_folder.join("output.wtns").exists()
+        {
+            proof_generator_sender
+                .send(ProofGenerator::new(
+                    uuid,
+                    unfinished_proof.proof_type,
+                    circuit_name,
+                    zkey_file_path.clone(),
+                ))
+                .await;
+            resumed += 1;
+        } else if tmp_folder.join("input.json").exists() {
+            witness_generator_sender
+                .send(WitnessGenerator::new(
+                    uuid,
+                    unfinished_proof.proof_type,
+                    circuit_name,
+                ))
+                .await;
+            resumed += 1;
+        } else {
+            let failure = Failure::new(None, Some("restart"), RESTART_REASON);
+            cleanup(uuid, store, failure).await;
+            jobs.finish(&uuid);
+            failed += 1;
+        }
+    }
+
+    if resumed + failed > 0 {
+        println!(
+            "Recovered unfinished jobs: {} resumed, {} failed",
+            resumed, failed
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::db::{types::Status, ProofStore};
+use crate::types::{PurgeCounts, RetentionReport};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionMode {
+    /// Clear the proof, public inputs, request data and failure reason, keep the row for metrics
+    Scrub,
+    /// Delete the row
+    Delete,
+}
+
+struct PurgeState {
+    runs: u64,
+    last_run_at: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    last_run: PurgeCounts,
+    total: PurgeCounts,
+}
+
+//purges the requests that reached a terminal status more than max_age ago, the
+//disclose public inputs reveal attributes of the passport holder
+pub struct Retention {
+    store: Arc<dyn ProofStore>,
+    //None keeps every request forever
+    max_age: Option<Duration>,
+    mode: RetentionMode,
+    state: Mutex<PurgeState>,
+}
+
+impl Retention {
+    pub fn new(store: Arc<dyn ProofStore>, max_age: Option<Duration>, mode: RetentionMode) -> Self {
+        Self {
+            store,
+            max_age,
+            mode,
+            state: Mutex::new(PurgeState {
+                runs: 0,
+                last_run_at: None,
+                last_error: None,
+                last_run: PurgeCounts::default(),
+                total: PurgeCounts::default(),
+            }),
+        }
+    }
+
+    pub async fn purge(&self) {
+        let Some(max_age) = self.max_age else {
+            return;
+        };
+        let Ok(max_age) = chrono::Duration::from_std(max_age) else {
+            return;
+        };
+        let started_at = Utc::now();
+        let result = self.store.purge(started_at - max_age, self.mode).await;
+
+        let mut state = self.state.lock().unwrap();
+        state.runs += 1;
+        state.last_run_at = Some(started_at);
+        match result {
+            Ok(statuses) => {
+                let mut counts = PurgeCounts::default();
+                for status in statuses {
+                    counts.add(status);
+                }
+                if counts.requests() > 0 {
+                    println!("Purged {} finished requests", counts.requests());
+                }
+                state.total.merge(&counts);
+                state.last_run = counts;
+                state.last_error = None;
+            }
+            Err(e) => {
+                dbg!(&e);
+                state.last_run = PurgeCounts::default();
+                state.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    pub fn report(&self) -> RetentionReport {
+        let state = self.state.lock().unwrap();
+        RetentionReport {
+            enabled: self.max_age.is_some(),
+            retention_hours: self.max_age.map(|max_age| max_age.as_secs() / 3600),
+            mode: self.mode,
+            runs: state.runs,
+            last_run_at: state.last_run_at,
+            last_error: state.last_error.clone(),
+            last_run: state.last_run.clone(),
+            total: state.total.clone(),
+        }
+    }
+
+    pub fn spawn_purger(self: Arc<Self>, interval: Duration) {
+        if self.max_age.is_none() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                self.purge().await;
+            }
+        });
+    }
+}
+
+impl PurgeCounts {
+    fn add(&mut self, status: Status) {
+        match status {
+            Status::ProofGenererated => self.proof_generated += 1,
+            Status::Failed => self.failed += 1,
+            Status::Cancelled => self.cancelled += 1,
+            //only terminal requests are purged
+            Status::Pending | Status::WitnessGenerated => (),
+        }
+    }
+
+    fn merge(&mut self, counts: &PurgeCounts) {
+        self.proof_generated += counts.proof_generated;
+        self.failed += counts.failed;
+        self.cancelled += counts.cancelled;
+    }
+
+    fn requests(&self) -> u64 {
+        self.proof_generated + self.failed + self.cancelled
+    }
+}
@@ -0,0 +1,311 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use crate::db::ProofStore;
+
+//errors that may go away when the same operation is tried again
+pub trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for std::io::Error {
+    fn is_transient(&self) -> bool {
+        matches!(
+            self.kind(),
+            ErrorKind::Interrupted
+                | ErrorKind::WouldBlock
+                | ErrorKind::TimedOut
+                | ErrorKind::OutOfMemory
+                | ErrorKind::ResourceBusy
+                | ErrorKind::StorageFull
+        )
+    }
+}
+
+impl Transient for sqlx::Error {
+    fn is_transient(&self) -> bool {
+        match self {
+            sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => true,
+            sqlx::Error::Database(e) => match e.code() {
+                //connection exceptions, serialization failures, deadlocks,
+                //too many connections and server shutdowns
+                Some(code) => {
+                    code.starts_with("08")
+                        || matches!(code.as_ref(), "40001" | "40P01" | "53300" | "57P01")
+                        //SQLITE_BUSY and SQLITE_LOCKED, and their extended codes
+                        || code.parse::<i32>().is_ok_and(|code| matches!(code & 0xff, 5 | 6))
+                }
+                None => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, base_delay: Duration, max_delay: Duration) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+            max_delay,
+        }
+    }
+
+    //delay before the attempt that follows `attempt`, doubling every time
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    //runs a pipeline step of a job until it succeeds, fails permanently or runs out of attempts,
+    //every retry is counted on the proofs row
+    pub async fn run<T, E, F, Fut>(
+        &self,
+        uuid: uuid::Uuid,
+        db: &dyn ProofStore,
+        mut operation: F,
+    ) -> Result<T, E>
+    where
+        E: Transient + Debug,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Ok(value) => return Ok(value),
+                Err(e) if e.is_transient() && attempt < self.max_attempts => {
+                    dbg!(&e);
+                    let delay = self.delay(attempt);
+                    attempt += 1;
+                    println!(
+                        "Retrying {} in {}ms (attempt {}/{})",
+                        uuid,
+                        delay.as_millis(),
+                        attempt,
+                        self.max_attempts
+                    );
+                    if let Err(e) = db.record_attempt(uuid).await {
+                        dbg!(&e);
+                    }
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::db::memory::MemoryStore;
+    use crate::db::NewProof;
+    use crate::generator::{GeneratorError, Stage};
+    use crate::types::ProofType;
+
+    //a database error carrying only a code, as Postgres and SQLite report them
+    #[derive(Debug)]
+    struct CodedError(&'static str);
+
+    impl std::fmt::Display for CodedError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "error {}", self.0)
+        }
+    }
+
+    impl std::error::Error for CodedError {}
+
+    impl sqlx::error::DatabaseError for CodedError {
+        fn message(&self) -> &str {
+            self.0
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.0))
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> sqlx::error::ErrorKind {
+            sqlx::error::ErrorKind::Other
+        }
+    }
+
+    fn database_error(code: &'static str) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(CodedError(code)))
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(100), Duration::from_secs(1));
+        let delays: Vec<_> = (1..=6).map(|attempt| policy.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        //the factor saturates instead of overflowing
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(1));
+        assert_eq!(
+            RetryPolicy::new(0, Duration::ZERO, Duration::ZERO).max_attempts,
+            1
+        );
+    }
+
+    #[test]
+    fn io_errors_are_transient_when_the_resource_may_come_back() {
+        for kind in [
+            ErrorKind::Interrupted,
+            ErrorKind::TimedOut,
+            ErrorKind::StorageFull,
+        ] {
+            assert!(std::io::Error::from(kind).is_transient(), "{:?}", kind);
+        }
+        for kind in [
+            ErrorKind::NotFound,
+            ErrorKind::PermissionDenied,
+            ErrorKind::InvalidData,
+        ] {
+            assert!(!std::io::Error::from(kind).is_transient(), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn database_errors_are_transient_by_code() {
+        assert!(sqlx::Error::PoolTimedOut.is_transient());
+        assert!(sqlx::Error::Io(ErrorKind::ConnectionReset.into()).is_transient());
+        assert!(!sqlx::Error::RowNotFound.is_transient());
+
+        //connection exception, serialization failure, deadlock, too many connections,
+        //admin shutdown, SQLITE_BUSY and SQLITE_LOCKED_SHAREDCACHE
+        for code in ["08006", "40001", "40P01", "53300", "57P01", "5", "262"] {
+            assert!(database_error(code).is_transient(), "{}", code);
+        }
+        //unique violation, syntax error, SQLITE_CONSTRAINT
+        for code in ["23505", "42601", "19"] {
+            assert!(!database_error(code).is_transient(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn only_a_sigkill_without_timeout_retries_a_stage() {
+        let exited = |code, signal| GeneratorError::Exited {
+            code,
+            signal,
+            stderr: String::new(),
+        };
+        assert!(exited(None, Some(libc::SIGKILL)).is_transient());
+        assert!(!exited(None, Some(libc::SIGSEGV)).is_transient());
+        assert!(!exited(Some(1), None).is_transient());
+        assert!(!GeneratorError::Timeout {
+            stage: Stage::Witness,
+            after: Duration::from_secs(1),
+        }
+        .is_transient());
+        assert!(!GeneratorError::Cancelled.is_transient());
+        assert!(GeneratorError::Io(ErrorKind::Interrupted.into()).is_transient());
+    }
+
+    async fn store_with_request(uuid: uuid::Uuid) -> MemoryStore {
+        let store = MemoryStore::new();
+        store
+            .create_proof_status(NewProof {
+                uuid,
+                proof_type: &ProofType::Disclose,
+                circuit_name: "vc_and_disclose",
+                on_chain: false,
+                endpoint_type: None,
+                endpoint: None,
+                version: 1,
+                user_defined_data: "",
+                self_defined_data: "",
+                instance_id: "a",
+                encrypt_result: false,
+            })
+            .await
+            .unwrap();
+        store
+    }
+
+    async fn attempts(store: &MemoryStore, uuid: uuid::Uuid) -> i32 {
+        store
+            .get_proof_status(uuid)
+            .await
+            .unwrap()
+            .unwrap()
+            .attempts
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried_until_the_last_attempt() {
+        let uuid = uuid::Uuid::new_v4();
+        let store = store_with_request(uuid).await;
+        let policy = RetryPolicy::new(3, Duration::ZERO, Duration::ZERO);
+        let calls = AtomicU32::new(0);
+
+        let result: Result<(), _> = policy
+            .run(uuid, &store, || async {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Err(std::io::Error::from(ErrorKind::TimedOut))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+        //the first attempt is not a retry
+        assert_eq!(attempts(&store, uuid).await, 1 + 2);
+
+        let calls = AtomicU32::new(0);
+        let result = policy
+            .run(uuid, &store, || async {
+                match calls.fetch_add(1, Ordering::Relaxed) {
+                    0 => Err(std::io::Error::from(ErrorKind::Interrupted)),
+                    _ => Ok("done"),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), "done");
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_are_not_retried() {
+        let uuid = uuid::Uuid::new_v4();
+        let store = store_with_request(uuid).await;
+        let before = attempts(&store, uuid).await;
+        let policy = RetryPolicy::new(3, Duration::ZERO, Duration::ZERO);
+        let calls = AtomicU32::new(0);
+
+        let result: Result<(), _> = policy
+            .run(uuid, &store, || async {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Err(std::io::Error::from(ErrorKind::NotFound))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(attempts(&store, uuid).await, before);
+    }
+}
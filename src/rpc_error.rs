@@ -0,0 +1,149 @@
+use jsonrpsee::types::{ErrorCode, ErrorObjectOwned};
+use serde::Serialize;
+
+use crate::db::types::Status;
+use crate::jobs::QueueFull;
+use crate::rate_limit::RateLimited;
+use crate::store::SessionError;
+
+//errors of the openpassport namespace, the variant fields are sent as the error data
+//together with the variant name, e.g. {"error": "session_expired", "uuid": "..."}
+#[derive(Debug, Serialize)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum RpcError {
+    SessionNotFound { uuid: uuid::Uuid },
+    DecryptionFailed { uuid: uuid::Uuid },
+    UnsupportedProofType { allowed: &'static str },
+    CircuitNotFound { circuit_name: String },
+    QueueFull { retry_after_secs: u64 },
+    DuplicateRequest { uuid: uuid::Uuid },
+    SessionExpired { uuid: uuid::Uuid },
+    RequestNotFound { uuid: uuid::Uuid },
+    NotCancellable { uuid: uuid::Uuid },
+    ResultNotReady { uuid: uuid::Uuid, status: Status },
+    NoProof { uuid: uuid::Uuid, status: Status },
+    ResultNotSealed { uuid: uuid::Uuid },
+    HpkeKeyNotFound { key_id: u32 },
+    RateLimited { retry_after_secs: u64 },
+    ResultPurged { uuid: uuid::Uuid },
+    InvalidRequest { reason: String },
+    InvalidParams { reason: String },
+    Internal { reason: String },
+}
+
+impl RpcError {
+    //application errors use codes from -32010 downwards, inside the range JSON-RPC
+    //reserves for servers, the codes are stable and clients may branch on them
+    pub fn code(&self) -> i32 {
+        match self {
+            RpcError::SessionNotFound { .. } => -32010,
+            RpcError::DecryptionFailed { .. } => -32011,
+            RpcError::UnsupportedProofType { .. } => -32012,
+            RpcError::CircuitNotFound { .. } => -32013,
+            RpcError::QueueFull { .. } => -32014,
+            RpcError::DuplicateRequest { .. } => -32015,
+            RpcError::SessionExpired { .. } => -32016,
+            RpcError::RequestNotFound { .. } => -32017,
+            RpcError::NotCancellable { .. } => -32018,
+            RpcError::ResultNotReady { .. } => -32019,
+            RpcError::NoProof { .. } => -32020,
+            RpcError::ResultNotSealed { .. } => -32021,
+            RpcError::HpkeKeyNotFound { .. } => -32022,
+            RpcError::RateLimited { .. } => -32023,
+            RpcError::ResultPurged { .. } => -32024,
+            RpcError::InvalidRequest { .. } => ErrorCode::InvalidRequest.code(),
+            RpcError::InvalidParams { .. } => ErrorCode::InvalidParams.code(),
+            RpcError::Internal { .. } => ErrorCode::InternalError.code(),
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            RpcError::SessionNotFound { .. } => "UUID not found".to_string(),
+            RpcError::DecryptionFailed { .. } => "Failed to decrypt text".to_string(),
+            RpcError::UnsupportedProofType { allowed } => {
+                format!("This endpoint only allows {} inputs", allowed)
+            }
+            RpcError::CircuitNotFound { circuit_name } => {
+                format!("Could not find the given circuit name: {}", circuit_name)
+            }
+            RpcError::QueueFull { retry_after_secs } => {
+                format!("Server is busy, retry in {} seconds", retry_after_secs)
+            }
+            RpcError::DuplicateRequest { .. } => "UUID already in use".to_string(),
+            RpcError::SessionExpired { .. } => {
+                "Session expired, call openpassport_hello again".to_string()
+            }
+            RpcError::RequestNotFound { .. } => "UUID not found".to_string(),
+            RpcError::NotCancellable { .. } => {
+                "Request not found or no longer cancellable".to_string()
+            }
+            RpcError::ResultNotReady { .. } => "Proof not generated yet".to_string(),
+            RpcError::NoProof { .. } => "No proof was generated".to_string(),
+            RpcError::ResultNotSealed { .. } => {
+                "The result is not encrypted, use openpassport_status".to_string()
+            }
+            RpcError::HpkeKeyNotFound { .. } => {
+                "Unknown or expired HPKE key, call openpassport_hpke_key again".to_string()
+            }
+            RpcError::RateLimited { retry_after_secs } => {
+                format!("Too many requests, retry in {} seconds", retry_after_secs)
+            }
+            RpcError::ResultPurged { .. } => {
+                "The result was purged after the retention period".to_string()
+            }
+            RpcError::InvalidRequest { reason }
+            | RpcError::InvalidParams { reason }
+            | RpcError::Internal { reason } => reason.clone(),
+        }
+    }
+
+    pub fn invalid_request(reason: impl Into<String>) -> Self {
+        RpcError::InvalidRequest {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn invalid_params(reason: impl Into<String>) -> Self {
+        RpcError::InvalidParams {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn internal(reason: impl Into<String>) -> Self {
+        RpcError::Internal {
+            reason: reason.into(),
+        }
+    }
+
+    //a consumed session means the submission was replayed
+    pub fn session(uuid: uuid::Uuid, error: SessionError) -> Self {
+        match error {
+            SessionError::NotFound => RpcError::SessionNotFound { uuid },
+            SessionError::Expired => RpcError::SessionExpired { uuid },
+            SessionError::Consumed => RpcError::DuplicateRequest { uuid },
+        }
+    }
+}
+
+impl From<RpcError> for ErrorObjectOwned {
+    fn from(error: RpcError) -> Self {
+        ErrorObjectOwned::owned(error.code(), error.message(), Some(error))
+    }
+}
+
+impl From<QueueFull> for RpcError {
+    fn from(queue_full: QueueFull) -> Self {
+        RpcError::QueueFull {
+            retry_after_secs: queue_full.retry_after.as_secs().max(1),
+        }
+    }
+}
+
+impl From<RateLimited> for RpcError {
+    fn from(rate_limited: RateLimited) -> Self {
+        RpcError::RateLimited {
+            retry_after_secs: rate_limited.retry_after.as_secs_f64().ceil() as u64,
+        }
+    }
+}
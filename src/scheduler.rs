@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Semaphore;
+
+use crate::types::ProofType;
+
+//implemented by the jobs of every pipeline stage
+pub trait Prioritized {
+    fn uuid(&self) -> uuid::Uuid;
+    fn proof_type(&self) -> ProofType;
+    fn circuit_name(&self) -> &str;
+}
+
+pub fn default_weight(proof_type: &ProofType) -> u32 {
+    match proof_type {
+        ProofType::Disclose
+        | ProofType::DiscloseId
+        | ProofType::DiscloseAadhaar
+        | ProofType::DiscloseKyc => 4,
+        ProofType::Dsc | ProofType::DscId => 2,
+        ProofType::Register
+        | ProofType::RegisterId
+        | ProofType::RegisterAadhaar
+        | ProofType::RegisterKyc => 1,
+    }
+}
+
+//parses a "<proof_type>=<weight>" command line value
+pub fn parse_weight(value: &str) -> Result<(String, u32), String> {
+    let (name, weight) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected <proof_type>=<weight>, got {}", value))?;
+    let known = (0..)
+        .map_while(|id| ProofType::try_from(id).ok())
+        .any(|proof_type| proof_type.to_string() == name);
+    if !known {
+        return Err(format!("unknown proof type: {}", name));
+    }
+    let weight = weight
+        .parse::<u32>()
+        .map_err(|e| format!("invalid weight {}: {}", weight, e))?;
+    Ok((name.to_string(), weight))
+}
+
+pub struct PriorityPolicy {
+    weights: HashMap<String, u32>,
+    circuit_sizes: HashMap<String, u32>,
+    aging: Duration,
+}
+
+impl PriorityPolicy {
+    pub fn new(
+        weights: HashMap<String, u32>,
+        circuit_sizes: HashMap<String, u32>,
+        aging: Duration,
+    ) -> Self {
+        Self {
+            weights,
+            circuit_sizes,
+            aging,
+        }
+    }
+
+    fn weight(&self, proof_type: &ProofType) -> u32 {
+        match self.weights.get(&proof_type.to_string()) {
+            Some(weight) => *weight,
+            None => default_weight(proof_type),
+        }
+    }
+
+    fn circuit_size(&self, circuit_name: &str) -> u32 {
+        self.circuit_sizes.get(circuit_name).copied().unwrap_or(0)
+    }
+
+    //a job gains one weight step for every aging interval it waits, so
+    //heavy, low weight jobs still get their turn under a steady stream of light ones
+    fn effective_weight(&self, weight: u32, enqueued_at: Instant, now: Instant) -> u64 {
+        let waited = now.saturating_duration_since(enqueued_at);
+        let steps = match self.aging.as_millis() {
+            0 => 0,
+            aging => waited.as_millis() / aging,
+        };
+        weight as u64 + steps.min(u64::MAX as u128) as u64
+    }
+}
+
+struct Entry<T> {
+    item: T,
+    weight: u32,
+    circuit_size: u32,
+    enqueued_at: Instant,
+    seq: u64,
+}
+
+struct Shared<T> {
+    entries: Mutex<Vec<Entry<T>>>,
+    //free places in the queue
+    slots: Semaphore,
+    //entries that have not been handed to the receiver yet
+    items: Semaphore,
+    next_seq: AtomicU64,
+    policy: Arc<PriorityPolicy>,
+    capacity: usize,
+}
+
+//bounded queue that hands out the highest priority job first, highest weight
+//first, then smallest circuit, then oldest
+pub fn channel<T: Prioritized>(
+    capacity: usize,
+    policy: Arc<PriorityPolicy>,
+) -> (PrioritySender<T>, PriorityReceiver<T>) {
+    let shared = Arc::new(Shared {
+        entries: Mutex::new(Vec::with_capacity(capacity)),
+        slots: Semaphore::new(capacity),
+        items: Semaphore::new(0),
+        next_seq: AtomicU64::new(0),
+        policy,
+        capacity,
+    });
+    (
+        PrioritySender {
+            shared: Arc::clone(&shared),
+        },
+        PriorityReceiver { shared },
+    )
+}
+
+pub struct PrioritySender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for PrioritySender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T: Prioritized> PrioritySender<T> {
+    //waits for a free place in the queue
+    pub async fn send(&self, item: T) {
+        self.shared
+            .slots
+            .acquire()
+            .await
+            .expect("slots semaphore is never closed")
+            .forget();
+
+        let policy = &self.shared.policy;
+        let entry = Entry {
+            weight: policy.weight(&item.proof_type()),
+            circuit_size: policy.circuit_size(item.circuit_name()),
+            enqueued_at: Instant::now(),
+            seq: self.shared.next_seq.fetch_add(1, Ordering::Relaxed),
+            item,
+        };
+        self.shared.entries.lock().unwrap().push(entry);
+        self.shared.items.add_permits(1);
+    }
+
+    //jobs waiting in the queue
+    pub fn len(&self) -> usize {
+        self.shared.entries.lock().unwrap().len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    //withdraws a queued job, None if it is not queued or the receiver is already taking it
+    pub fn remove(&self, uuid: uuid::Uuid) -> Option<T> {
+        let mut entries = self.shared.entries.lock().unwrap();
+        let index = entries.iter().position(|entry| entry.item.uuid() == uuid)?;
+        //without a free items permit every entry is promised to a waiting receiver
+        self.shared.items.try_acquire().ok()?.forget();
+        let entry = entries.swap_remove(index);
+        drop(entries);
+        self.shared.slots.add_permits(1);
+        Some(entry.item)
+    }
+}
+
+pub struct PriorityReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> PriorityReceiver<T> {
+    pub async fn recv(&mut self) -> T {
+        self.shared
+            .items
+            .acquire()
+            .await
+            .expect("items semaphore is never closed")
+            .forget();
+
+        let item = {
+            let mut entries = self.shared.entries.lock().unwrap();
+            let policy = &self.shared.policy;
+            let now = Instant::now();
+            //every items permit matches an entry, so the queue cannot be empty here
+            let (index, _) = entries
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, entry)| {
+                    (
+                        policy.effective_weight(entry.weight, entry.enqueued_at, now),
+                        std::cmp::Reverse(entry.circuit_size),
+                        std::cmp::Reverse(entry.seq),
+                    )
+                })
+                .expect("queue is not empty");
+            entries.swap_remove(index).item
+        };
+        self.shared.slots.add_permits(1);
+        item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Job {
+        uuid: uuid::Uuid,
+        proof_type: ProofType,
+        circuit_name: &'static str,
+    }
+
+    impl Prioritized for Job {
+        fn uuid(&self) -> uuid::Uuid {
+            self.uuid
+        }
+        fn proof_type(&self) -> ProofType {
+            self.proof_type.clone()
+        }
+        fn circuit_name(&self) -> &str {
+            self.circuit_name
+        }
+    }
+
+    fn job(proof_type: ProofType, circuit_name: &'static str) -> Job {
+        Job {
+            uuid: uuid::Uuid::new_v4(),
+            proof_type,
+            circuit_name,
+        }
+    }
+
+    fn policy(aging: Duration) -> Arc<PriorityPolicy> {
+        let circuit_sizes = HashMap::from([("small".to_string(), 1), ("large".to_string(), 10)]);
+        Arc::new(PriorityPolicy::new(HashMap::new(), circuit_sizes, aging))
+    }
+
+    #[tokio::test]
+    async fn highest_weight_then_smallest_circuit_then_oldest() {
+        let (sender, mut receiver) = channel(8, policy(Duration::ZERO));
+        let register = job(ProofType::Register, "small");
+        let dsc = job(ProofType::Dsc, "small");
+        let disclose_large = job(ProofType::Disclose, "large");
+        let disclose_first = job(ProofType::Disclose, "small");
+        let disclose_second = job(ProofType::Disclose, "small");
+        let expected = [
+            disclose_first.uuid,
+            disclose_second.uuid,
+            disclose_large.uuid,
+            dsc.uuid,
+            register.uuid,
+        ];
+        for job in [
+            register,
+            dsc,
+            disclose_large,
+            disclose_first,
+            disclose_second,
+        ] {
+            sender.send(job).await;
+        }
+
+        for uuid in expected {
+            assert_eq!(receiver.recv().await.uuid, uuid);
+        }
+    }
+
+    #[tokio::test]
+    async fn configured_weights_override_the_defaults() {
+        let weights = HashMap::from([("register".to_string(), 10)]);
+        let policy = Arc::new(PriorityPolicy::new(weights, HashMap::new(), Duration::ZERO));
+        let (sender, mut receiver) = channel(8, policy);
+        let disclose = job(ProofType::Disclose, "small");
+        let register = job(ProofType::Register, "small");
+        let register_uuid = register.uuid;
+        sender.send(disclose).await;
+        sender.send(register).await;
+
+        assert_eq!(receiver.recv().await.uuid, register_uuid);
+    }
+
+    #[tokio::test]
+    async fn waiting_jobs_are_promoted() {
+        let aging = Duration::from_millis(20);
+        let (sender, mut receiver) = channel(8, policy(aging));
+        let register = job(ProofType::Register, "small");
+        let register_uuid = register.uuid;
+        sender.send(register).await;
+        //disclose outweighs register by 3, four aging steps put register ahead
+        tokio::time::sleep(aging * 4).await;
+        sender.send(job(ProofType::Disclose, "small")).await;
+
+        assert_eq!(receiver.recv().await.uuid, register_uuid);
+    }
+
+    #[test]
+    fn effective_weight_grows_with_each_aging_interval() {
+        let aging = policy(Duration::from_secs(10));
+        let enqueued_at = Instant::now();
+        assert_eq!(aging.effective_weight(1, enqueued_at, enqueued_at), 1);
+        assert_eq!(
+            aging.effective_weight(1, enqueued_at, enqueued_at + Duration::from_secs(9)),
+            1
+        );
+        assert_eq!(
+            aging.effective_weight(1, enqueued_at, enqueued_at + Duration::from_secs(35)),
+            4
+        );
+        //no aging keeps the configured weight
+        let no_aging = policy(Duration::ZERO);
+        assert_eq!(
+            no_aging.effective_weight(1, enqueued_at, enqueued_at + Duration::from_secs(35)),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn removed_job_is_not_received() {
+        let (sender, mut receiver) = channel(2, policy(Duration::ZERO));
+        let removed = job(ProofType::Disclose, "small");
+        let removed_uuid = removed.uuid;
+        let kept = job(ProofType::Register, "small");
+        let kept_uuid = kept.uuid;
+        sender.send(removed).await;
+        sender.send(kept).await;
+
+        assert_eq!(sender.remove(removed_uuid).unwrap().uuid, removed_uuid);
+        assert!(sender.remove(removed_uuid).is_none());
+        assert_eq!(sender.len(), 1);
+        assert_eq!(receiver.recv().await.uuid, kept_uuid);
+    }
+
+    #[test]
+    fn parse_weight_checks_the_proof_type() {
+        assert_eq!(
+            parse_weight("register_id=3"),
+            Ok(("register_id".to_string(), 3))
+        );
+        assert!(parse_weight("register").is_err());
+        assert!(parse_weight("unknown=3").is_err());
+        assert!(parse_weight("register=-1").is_err());
+    }
+}
@@ -1,19 +1,36 @@
-use base64::engine::{general_purpose, Engine};
-use jsonrpsee::core::async_trait;
+use jsonrpsee::core::{async_trait, SubscriptionResult};
 use jsonrpsee::proc_macros::rpc;
-use jsonrpsee::types;
-use jsonrpsee::{types::ErrorObjectOwned, ResponsePayload};
+use jsonrpsee::server::{
+    serve_with_graceful_shutdown, stop_channel, HttpRequest, RpcServiceBuilder, Server,
+    ServerHandle,
+};
+use jsonrpsee::ResponsePayload;
+use jsonrpsee::{Methods, PendingSubscriptionSink, SubscriptionMessage};
 use p256::ecdh::EphemeralSecret;
 use p256::elliptic_curve::sec1::ToEncodedPoint;
 use p256::elliptic_curve::PublicKey;
 use rand_core::OsRng;
-use sqlx::Pool;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, OwnedSemaphorePermit};
+use tower::Service;
 
-use crate::db::create_proof_status;
+use crate::attestation::{AttestationProvider, AttestationRequest};
+use crate::db::types::{FailureStage, ProofStatus, Status, StatusUpdate};
+use crate::db::{Failure, NewProof, ProofStore};
+use crate::hpke::{HpkeKeyRing, SUBMIT_SEALED_INFO};
+use crate::jobs::JobRegistry;
+use crate::key_schedule::{ProtocolVersion, SessionKeys};
+use crate::rate_limit::{ClientIp, RateLimit, RateLimiter};
+use crate::retention::Retention;
+use crate::rpc_error::RpcError;
 use crate::store::LruStore;
-use crate::types::{ProofRequest, SubmitRequest};
+use crate::types::{
+    EncryptedPayload, HpkeKeyResponse, ProofRequest, QueueDepths, RateLimitStats, RetentionReport,
+    SubmitRequest,
+};
 use crate::utils;
 use crate::{generator::file_generator::FileGenerator, types::HelloResponse};
 
@@ -26,6 +43,7 @@ pub trait Rpc {
         &self,
         user_pubkey: Vec<u8>,
         uuid: uuid::Uuid,
+        protocol_version: Option<u8>,
     ) -> ResponsePayload<'static, HelloResponse>;
     #[method(name = "submit_request")]
     async fn submit_request(
@@ -35,27 +53,102 @@ pub trait Rpc {
         cipher_text: Vec<u8>,
         auth_tag: Vec<u8>,
     ) -> ResponsePayload<'static, String>;
+    #[method(name = "hpke_key")]
+    async fn hpke_key(&self) -> ResponsePayload<'static, HpkeKeyResponse>;
+    #[method(name = "submit_sealed")]
+    async fn submit_sealed(
+        &self,
+        uuid: uuid::Uuid,
+        key_id: u32,
+        enc: Vec<u8>,
+        cipher_text: Vec<u8>,
+    ) -> ResponsePayload<'static, String>;
+    #[method(name = "cancel")]
+    async fn cancel(
+        &self,
+        uuid: uuid::Uuid,
+        nonce: Vec<u8>,
+        cipher_text: Vec<u8>,
+        auth_tag: Vec<u8>,
+    ) -> ResponsePayload<'static, String>;
+    #[method(name = "attestation")]
+    async fn attestation(
+        &self,
+        user_data: Option<Vec<u8>>,
+        nonce: Option<Vec<u8>>,
+        public_key: Option<Vec<u8>>,
+    ) -> ResponsePayload<'static, Vec<u8>>;
+    #[method(name = "status")]
+    async fn status(&self, uuid: uuid::Uuid) -> ResponsePayload<'static, ProofStatus>;
+    #[method(name = "fetch_result")]
+    async fn fetch_result(&self, uuid: uuid::Uuid) -> ResponsePayload<'static, EncryptedPayload>;
+    #[subscription(name = "subscribe_status" => "status_update", unsubscribe = "unsubscribe_status", item = ProofStatus)]
+    async fn subscribe_status(&self, uuid: uuid::Uuid) -> SubscriptionResult;
+}
+
+//operator methods, served on the admin address only
+#[rpc(server, namespace = "openpassport")]
+pub trait AdminRpc {
+    #[method(name = "queue_depth")]
+    async fn queue_depth(&self) -> ResponsePayload<'static, QueueDepths>;
+    #[method(name = "rate_limits")]
+    async fn rate_limits(&self) -> ResponsePayload<'static, RateLimitStats>;
+    #[method(name = "retention")]
+    async fn retention(&self) -> ResponsePayload<'static, RetentionReport>;
 }
 
 pub struct RpcServerImpl {
-    store: LruStore,
-    file_generator_sender: tokio::sync::mpsc::Sender<FileGenerator>,
+    store: Arc<LruStore>,
+    jobs: Arc<JobRegistry>,
     circuit_zkey_map: Arc<HashMap<String, String>>,
-    db: Pool<sqlx::Postgres>,
+    db: Arc<dyn ProofStore>,
+    status_update_sender: broadcast::Sender<StatusUpdate>,
+    attestation_provider: Arc<dyn AttestationProvider>,
+    hpke_keys: Arc<HpkeKeyRing>,
+    instance_id: String,
 }
 
 impl RpcServerImpl {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        store: LruStore,
-        file_generator_sender: tokio::sync::mpsc::Sender<FileGenerator>,
+        store: Arc<LruStore>,
+        jobs: Arc<JobRegistry>,
         circuit_zkey_map: Arc<HashMap<String, String>>,
-        db: Pool<sqlx::Postgres>,
+        db: Arc<dyn ProofStore>,
+        status_update_sender: broadcast::Sender<StatusUpdate>,
+        attestation_provider: Arc<dyn AttestationProvider>,
+        hpke_keys: Arc<HpkeKeyRing>,
+        instance_id: String,
     ) -> Self {
         Self {
             store,
-            file_generator_sender,
+            jobs,
             circuit_zkey_map,
             db,
+            status_update_sender,
+            attestation_provider,
+            hpke_keys,
+            instance_id,
+        }
+    }
+}
+
+pub struct AdminRpcServerImpl {
+    jobs: Arc<JobRegistry>,
+    rate_limiter: Arc<RateLimiter>,
+    retention: Arc<Retention>,
+}
+
+impl AdminRpcServerImpl {
+    pub fn new(
+        jobs: Arc<JobRegistry>,
+        rate_limiter: Arc<RateLimiter>,
+        retention: Arc<Retention>,
+    ) -> Self {
+        Self {
+            jobs,
+            rate_limiter,
+            retention,
         }
     }
 }
@@ -70,15 +163,22 @@ impl RpcServer for RpcServerImpl {
         &self,
         user_pubkey: Vec<u8>,
         uuid: uuid::Uuid,
+        protocol_version: Option<u8>,
     ) -> ResponsePayload<'static, HelloResponse> {
         if user_pubkey.len() != 33 {
-            return ResponsePayload::error(ErrorObjectOwned::owned::<String>(
-                types::ErrorCode::InvalidRequest.code(), //BAD_REQUEST
+            return ResponsePayload::error(RpcError::invalid_request(
                 "Public key must be 33 bytes",
-                None,
             ));
         };
 
+        //clients that predate the key schedule do not send a version
+        let protocol_version = match ProtocolVersion::try_from(protocol_version.unwrap_or(0)) {
+            Ok(protocol_version) => protocol_version,
+            Err(e) => {
+                return ResponsePayload::error(RpcError::invalid_params(e));
+            }
+        };
+
         let mut rng = OsRng;
         let my_private_key = EphemeralSecret::random(&mut rng);
         let my_public_key = PublicKey::from(&my_private_key);
@@ -86,11 +186,7 @@ impl RpcServer for RpcServerImpl {
         let their_public_key = match PublicKey::from_sec1_bytes(&user_pubkey) {
             Ok(pubkey) => pubkey,
             Err(err) => {
-                return ResponsePayload::error(ErrorObjectOwned::owned::<String>(
-                    types::ErrorCode::InvalidParams.code(), //INVALID_PARAMS
-                    format!("{:?}", err),
-                    None,
-                ));
+                return ResponsePayload::error(RpcError::invalid_params(format!("{:?}", err)));
             }
         };
 
@@ -98,50 +194,44 @@ impl RpcServer for RpcServerImpl {
             their_public_key.to_encoded_point(true).to_bytes().to_vec();
         let my_public_key_compressed = my_public_key.to_encoded_point(true).to_bytes().to_vec();
 
-        let their_public_key_string =
-            general_purpose::STANDARD.encode(&their_public_key_compressed);
-        let my_public_key_string = general_purpose::STANDARD.encode(&my_public_key_compressed);
-
-        let attestation = match utils::attestation::get_custom_token_bytes(vec![
-            &their_public_key_string,
-            &my_public_key_string,
-        ])
-        .await
+        let attestation = match self
+            .attestation_provider
+            .attest(AttestationRequest {
+                user_data: Some(their_public_key_compressed.clone()),
+                nonce: None,
+                public_key: Some(my_public_key_compressed.clone()),
+            })
+            .await
         {
             Ok(attestation) => attestation,
             Err(err) => {
-                return ResponsePayload::error(ErrorObjectOwned::owned::<String>(
-                    types::ErrorCode::InternalError.code(), //INTERNAL_SERVER_ERROR
-                    format!("{:?}", err),
-                    None,
-                ));
+                return ResponsePayload::error(RpcError::internal(format!("{:?}", err)));
             }
         };
 
-        let derived_key_result = my_private_key
-            .diffie_hellman(&their_public_key)
-            .raw_secret_bytes()
-            .to_vec();
+        let shared_secret = my_private_key.diffie_hellman(&their_public_key);
+        let session_keys = SessionKeys::derive(
+            protocol_version,
+            shared_secret.raw_secret_bytes().as_ref(),
+            &uuid,
+            &their_public_key_compressed,
+            &my_public_key_compressed,
+        );
 
         match self
             .store
-            .insert_new_agreement(uuid, derived_key_result)
+            .insert_new_agreement(uuid, session_keys)
             .await
         {
             Ok(_) => (),
             Err(_) => {
-                return ResponsePayload::error(ErrorObjectOwned::owned::<String>(
-                    types::ErrorCode::InvalidRequest.code(), //INTERNAL_SERVER_ERROR
-                    "UUID already exists",
-                    None,
-                ));
+                return ResponsePayload::error(RpcError::DuplicateRequest { uuid });
             }
         }
 
-        ResponsePayload::success(HelloResponse::new(uuid, attestation).into())
+        ResponsePayload::success(HelloResponse::new(uuid, attestation, protocol_version))
     }
 
-    //TODO: check if circuit exists
     async fn submit_request(
         &self,
         uuid: uuid::Uuid,
@@ -150,78 +240,314 @@ impl RpcServer for RpcServerImpl {
         auth_tag: Vec<u8>,
     ) -> ResponsePayload<'static, String> {
         if nonce.len() != 12 {
-            return ResponsePayload::error(ErrorObjectOwned::owned::<String>(
-                types::ErrorCode::InvalidRequest.code(),
-                "Nonce must be 12 bytes",
-                None,
-            ));
+            return ResponsePayload::error(RpcError::invalid_request("Nonce must be 12 bytes"));
         }
         if auth_tag.len() != 16 {
-            return ResponsePayload::error(ErrorObjectOwned::owned::<String>(
-                types::ErrorCode::InvalidRequest.code(),
-                "Auth tag must be 16 bytes",
-                None,
-            ));
+            return ResponsePayload::error(RpcError::invalid_request("Auth tag must be 16 bytes"));
         }
-        
+
+        //checked first, a busy server leaves the session untouched for a retry
+        let admission = match self.jobs.reserve() {
+            Ok(admission) => admission,
+            Err(queue_full) => return ResponsePayload::error(RpcError::from(queue_full)),
+        };
+
         let nonce = nonce.as_slice();
         let auth_tag = auth_tag.as_slice();
-        let key = {
-            let key = match self.store.get_shared_secret(&uuid).await {
-                Some(shared_secret) => shared_secret,
-                None => {
-                    self.store.remove_agreement(&uuid).await;
-                    return ResponsePayload::error(ErrorObjectOwned::owned::<String>(
-                        types::ErrorCode::InvalidRequest.code(),
-                        "UUID not found",
-                        None,
-                    ));
+        let session_keys = match self.store.session_keys(&uuid).await {
+            Ok(session_keys) => session_keys,
+            Err(e) => return ResponsePayload::error(RpcError::session(uuid, e)),
+        };
+
+        let decrypted_text =
+            match utils::decrypt(&session_keys.request_key, cipher_text, auth_tag, nonce) {
+            Ok(text) => text,
+            Err(_) => {
+                return ResponsePayload::error(RpcError::DecryptionFailed { uuid });
+            }
+        };
+
+        match self
+            .accept_request(uuid, decrypted_text.as_bytes(), &session_keys, admission)
+            .await
+        {
+            //a raced submission of the same session is refused by create_proof_status
+            Ok(()) => {
+                self.store.consume_session(&uuid).await;
+                ResponsePayload::success(uuid.to_string())
+            }
+            Err(e) => ResponsePayload::error(e),
+        }
+    }
+
+    async fn hpke_key(&self) -> ResponsePayload<'static, HpkeKeyResponse> {
+        let key = self.hpke_keys.current();
+        match key.attestation(self.attestation_provider.as_ref()).await {
+            Ok(attestation) => ResponsePayload::success(HpkeKeyResponse {
+                key_id: key.key_id,
+                public_key: key.public_key.clone(),
+                attestation,
+                expires_at: key.expires_at,
+            }),
+            Err(err) => ResponsePayload::error(RpcError::internal(err)),
+        }
+    }
+
+    //one round trip alternative to hello + submit_request, the request is sealed
+    //to the key from hpke_key and bound to the uuid through the aad
+    async fn submit_sealed(
+        &self,
+        uuid: uuid::Uuid,
+        key_id: u32,
+        enc: Vec<u8>,
+        cipher_text: Vec<u8>,
+    ) -> ResponsePayload<'static, String> {
+        let admission = match self.jobs.reserve() {
+            Ok(admission) => admission,
+            Err(queue_full) => return ResponsePayload::error(RpcError::from(queue_full)),
+        };
+
+        let key = match self.hpke_keys.get(key_id) {
+            Some(key) => key,
+            None => {
+                return ResponsePayload::error(RpcError::HpkeKeyNotFound { key_id });
+            }
+        };
+
+        let (plaintext, session_keys) =
+            match key.open(&enc, SUBMIT_SEALED_INFO, uuid.as_bytes(), &cipher_text) {
+                Ok(opened) => opened,
+                Err(_) => {
+                    return ResponsePayload::error(RpcError::DecryptionFailed { uuid });
                 }
             };
-            key
+
+        //claimed before accepting, so that a replay sent concurrently is rejected
+        if !key.try_consume(&enc) {
+            return ResponsePayload::error(RpcError::DuplicateRequest { uuid });
+        }
+
+        match self
+            .accept_request(uuid, &plaintext, &session_keys, admission)
+            .await
+        {
+            Ok(()) => ResponsePayload::success(uuid.to_string()),
+            Err(e) => {
+                key.release(&enc);
+                ResponsePayload::error(e)
+            }
+        }
+    }
+
+    //the client proves it owns the request by encrypting its uuid with the key agreed in hello
+    async fn cancel(
+        &self,
+        uuid: uuid::Uuid,
+        nonce: Vec<u8>,
+        cipher_text: Vec<u8>,
+        auth_tag: Vec<u8>,
+    ) -> ResponsePayload<'static, String> {
+        if nonce.len() != 12 {
+            return ResponsePayload::error(RpcError::invalid_request("Nonce must be 12 bytes"));
+        }
+        if auth_tag.len() != 16 {
+            return ResponsePayload::error(RpcError::invalid_request("Auth tag must be 16 bytes"));
+        }
+
+        let key = match self.jobs.request_key(&uuid) {
+            Some(key) => key,
+            None => {
+                return ResponsePayload::error(RpcError::NotCancellable { uuid });
+            }
         };
 
-        let key: [u8; 32] = match key.try_into() {
-            Ok(key) => key,
-            Err(_) => {
-                self.store.remove_agreement(&uuid).await;
-                return ResponsePayload::error(ErrorObjectOwned::owned::<String>(
-                    types::ErrorCode::InternalError.code(), //INTERNAL_SERVER_ERROR
-                    "Failed to store ephemeral key",
-                    None,
-                ));
+        match utils::decrypt(&key, cipher_text, &auth_tag, &nonce) {
+            Ok(text) if *text == uuid.to_string() => (),
+            _ => {
+                return ResponsePayload::error(RpcError::DecryptionFailed { uuid });
+            }
+        }
+
+        match self.db.cancel_proof(uuid).await {
+            Ok(true) => (),
+            Ok(false) => {
+                return ResponsePayload::error(RpcError::NotCancellable { uuid });
             }
+            Err(e) => {
+                dbg!(&e);
+                return ResponsePayload::error(RpcError::internal("Could not cancel the request"));
+            }
+        }
+
+        self.jobs.cancel(uuid).await;
+        ResponsePayload::success(uuid.to_string())
+    }
+
+    async fn attestation(
+        &self,
+        user_data: Option<Vec<u8>>,
+        nonce: Option<Vec<u8>>,
+        public_key: Option<Vec<u8>>,
+    ) -> ResponsePayload<'static, Vec<u8>> {
+        let request = AttestationRequest {
+            user_data,
+            nonce,
+            public_key,
         };
+        if let Err(e) = self.attestation_provider.validate(&request) {
+            return ResponsePayload::error(RpcError::invalid_params(e));
+        }
 
-        let decrypted_text: String = match utils::decrypt(key, cipher_text, auth_tag, nonce) {
-            Ok(text) => text,
-            Err(_) => {
-                self.store.remove_agreement(&uuid).await;
-                return ResponsePayload::error(ErrorObjectOwned::owned::<String>(
-                    types::ErrorCode::InvalidRequest.code(),
-                    "Failed to decrypt text",
-                    None,
-                ));
+        match self.attestation_provider.attest(request).await {
+            Ok(attestation) => ResponsePayload::success(attestation),
+            Err(err) => ResponsePayload::error(RpcError::internal(format!("{:?}", err))),
+        }
+    }
+
+    async fn status(&self, uuid: uuid::Uuid) -> ResponsePayload<'static, ProofStatus> {
+        match self.db.get_proof_status(uuid).await {
+            Ok(Some(proof_status)) => ResponsePayload::success(proof_status),
+            Ok(None) => ResponsePayload::error(RpcError::RequestNotFound { uuid }),
+            Err(e) => ResponsePayload::error(RpcError::internal(e)),
+        }
+    }
+
+    //the sealed proof and public inputs of a request submitted with encryptResult
+    async fn fetch_result(&self, uuid: uuid::Uuid) -> ResponsePayload<'static, EncryptedPayload> {
+        let sealed_result = match self.db.get_sealed_result(uuid).await {
+            Ok(Some(sealed_result)) => sealed_result,
+            Ok(None) => {
+                return ResponsePayload::error(RpcError::RequestNotFound { uuid });
+            }
+            Err(e) => {
+                return ResponsePayload::error(RpcError::internal(e));
+            }
+        };
+
+        if !sealed_result.encrypted_result {
+            return ResponsePayload::error(RpcError::ResultNotSealed { uuid });
+        }
+        if sealed_result.purged {
+            return ResponsePayload::error(RpcError::ResultPurged { uuid });
+        }
+        match sealed_result.status {
+            Status::ProofGenererated => (),
+            status if status.is_terminal() => {
+                return ResponsePayload::error(RpcError::NoProof { uuid, status });
+            }
+            _ => {
+                return ResponsePayload::error(RpcError::ResultNotReady {
+                    uuid,
+                    status: sealed_result.status,
+                });
+            }
+        }
+
+        match sealed_result
+            .sealed_result
+            .as_deref()
+            .and_then(EncryptedPayload::from_bytes)
+        {
+            Some(encrypted_payload) => ResponsePayload::success(encrypted_payload),
+            None => ResponsePayload::error(RpcError::internal("Sealed result is missing")),
+        }
+    }
+
+    async fn subscribe_status(
+        &self,
+        pending: PendingSubscriptionSink,
+        uuid: uuid::Uuid,
+    ) -> SubscriptionResult {
+        //subscribe before reading the current status so that no transition is missed
+        let mut status_update_receiver = self.status_update_sender.subscribe();
+
+        let current_status = match self.db.get_proof_status(uuid).await {
+            Ok(Some(proof_status)) => proof_status,
+            Ok(None) => {
+                pending.reject(RpcError::RequestNotFound { uuid }).await;
+                return Ok(());
+            }
+            Err(e) => {
+                pending.reject(RpcError::internal(e)).await;
+                return Ok(());
             }
         };
 
-        let submit_request = match serde_json::from_str::<SubmitRequest>(&decrypted_text) {
+        let sink = pending.accept().await?;
+        sink.send(SubscriptionMessage::from_json(&current_status)?)
+            .await?;
+        if current_status.status.is_terminal() {
+            return Ok(());
+        }
+
+        loop {
+            let proof_status = tokio::select! {
+                _ = sink.closed() => return Ok(()),
+                status_update = status_update_receiver.recv() => match status_update {
+                    Ok(StatusUpdate::Status(proof_status)) => proof_status,
+                    Ok(StatusUpdate::Resync) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                        //we may have skipped a transition, resend the latest state
+                        match self.db.get_proof_status(uuid).await {
+                            Ok(Some(proof_status)) => proof_status,
+                            _ => continue,
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+            };
+
+            if proof_status.request_id != uuid {
+                continue;
+            }
+
+            sink.send(SubscriptionMessage::from_json(&proof_status)?)
+                .await?;
+            if proof_status.status.is_terminal() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl AdminRpcServer for AdminRpcServerImpl {
+    //how loaded the server is, load balancers can route away from a server
+    //whose pending jobs reached their capacity
+    async fn queue_depth(&self) -> ResponsePayload<'static, QueueDepths> {
+        ResponsePayload::success(self.jobs.queue_depths())
+    }
+
+    async fn rate_limits(&self) -> ResponsePayload<'static, RateLimitStats> {
+        ResponsePayload::success(self.rate_limiter.stats())
+    }
+
+    async fn retention(&self) -> ResponsePayload<'static, RetentionReport> {
+        ResponsePayload::success(self.retention.report())
+    }
+}
+
+impl RpcServerImpl {
+    //checks a decrypted SubmitRequest and queues it, shared by submit_request and submit_sealed
+    async fn accept_request(
+        &self,
+        uuid: uuid::Uuid,
+        plaintext: &[u8],
+        session_keys: &SessionKeys,
+        admission: OwnedSemaphorePermit,
+    ) -> Result<(), RpcError> {
+        let submit_request = match serde_json::from_slice::<SubmitRequest>(plaintext) {
             Ok(submit_request) => {
-                let mut allowed_proof_type = "";
-                if cfg!(feature = "register") {
-                    allowed_proof_type = "register";
+                let allowed_proof_type = if cfg!(feature = "register") {
+                    "register"
                 } else if cfg!(feature = "dsc") {
-                    allowed_proof_type = "dsc";
+                    "dsc"
                 } else {
-                    allowed_proof_type = "disclose";
-                }
+                    "disclose"
+                };
 
-                let invalid_proof_type_response =
-                    ResponsePayload::error(ErrorObjectOwned::owned::<String>(
-                        types::ErrorCode::InvalidRequest.code(), //BAD REQUEST
-                        format!("This endpoint only allows {} inputs", allowed_proof_type),
-                        None,
-                    ));
+                let invalid_proof_type_error = RpcError::UnsupportedProofType {
+                    allowed: allowed_proof_type,
+                };
 
                 match submit_request.proof_request_type {
                     ProofRequest::Register { .. }
@@ -229,14 +555,12 @@ impl RpcServer for RpcServerImpl {
                     | ProofRequest::RegisterAadhaar { .. }
                     | ProofRequest::RegisterKyc { .. } => {
                         if !cfg!(feature = "register") && !cfg!(feature = "cherrypick") {
-                            self.store.remove_agreement(&uuid).await;
-                            return invalid_proof_type_response;
+                            return Err(invalid_proof_type_error);
                         }
                     }
                     ProofRequest::Dsc { .. } | ProofRequest::DscId { .. } => {
                         if !cfg!(feature = "dsc") && !cfg!(feature = "cherrypick") {
-                            self.store.remove_agreement(&uuid).await;
-                            return invalid_proof_type_response;
+                            return Err(invalid_proof_type_error);
                         }
                     }
                     ProofRequest::Disclose { .. }
@@ -244,30 +568,19 @@ impl RpcServer for RpcServerImpl {
                     | ProofRequest::DiscloseAadhaar { .. }
                     | ProofRequest::DiscloseKyc { .. } => {
                         if !cfg!(feature = "disclose") && !cfg!(feature = "cherrypick") {
-                            self.store.remove_agreement(&uuid).await;
-                            return invalid_proof_type_response;
+                            return Err(invalid_proof_type_error);
                         }
                     }
                 };
 
                 let circuit_name = submit_request.proof_request_type.circuit().name.clone();
                 if !self.circuit_zkey_map.contains_key(&circuit_name) {
-                    self.store.remove_agreement(&uuid).await;
-                    return ResponsePayload::error(ErrorObjectOwned::owned::<String>(
-                        types::ErrorCode::InvalidRequest.code(),
-                        format!("Could not find the given circuit name: {}", &circuit_name),
-                        None,
-                    ));
+                    return Err(RpcError::CircuitNotFound { circuit_name });
                 }
                 submit_request
             }
             Err(_) => {
-                self.store.remove_agreement(&uuid).await;
-                return ResponsePayload::error(ErrorObjectOwned::owned::<String>(
-                    types::ErrorCode::InvalidRequest.code(),
-                    "Failed to parse proof request",
-                    None,
-                ));
+                return Err(RpcError::invalid_request("Failed to parse proof request"));
             }
         };
 
@@ -277,7 +590,7 @@ impl RpcServer for RpcServerImpl {
                     endpoint_type,
                     endpoint,
                     ..
-                } => (endpoint_type.as_ref(), endpoint.as_ref(), "", "", 1 as i32),
+                } => (endpoint_type.as_ref(), endpoint.as_ref(), "", "", 1),
                 ProofRequest::Dsc {
                     endpoint_type,
                     endpoint,
@@ -363,42 +676,110 @@ impl RpcServer for RpcServerImpl {
                 ),
             };
 
-        if let Err(e) = create_proof_status(
-            uuid,
-            &(&submit_request.proof_request_type).into(),
-            &submit_request.proof_request_type.circuit().name,
-            submit_request.onchain,
-            &self.db,
-            endpoint_type,
-            endpoint,
-            version,
-            user_defined_data,
-            self_defined_data,
-        )
-        .await
+        match self
+            .db
+            .create_proof_status(NewProof {
+                uuid,
+                proof_type: &(&submit_request.proof_request_type).into(),
+                circuit_name: &submit_request.proof_request_type.circuit().name,
+                on_chain: submit_request.onchain,
+                endpoint_type,
+                endpoint,
+                version,
+                user_defined_data,
+                self_defined_data,
+                instance_id: &self.instance_id,
+                encrypt_result: submit_request.encrypt_result,
+            })
+            .await
         {
-            self.store.remove_agreement(&uuid).await;
-            return ResponsePayload::error(ErrorObjectOwned::owned::<String>(
-                types::ErrorCode::InternalError.code(), //INTERNAL_SERVER_ERROR
-                e,
-                None,
-            ));
-        }
-
-        let file_generator = FileGenerator::new(uuid.clone(), submit_request.proof_request_type);
-        match self.file_generator_sender.send(file_generator).await {
-            Ok(()) => (),
+            Ok(true) => (),
+            Ok(false) => {
+                return Err(RpcError::DuplicateRequest { uuid });
+            }
             Err(e) => {
-                self.store.remove_agreement(&uuid).await;
-                return ResponsePayload::error(ErrorObjectOwned::owned::<String>(
-                    types::ErrorCode::InternalError.code(), //INTERNAL_SERVER_ERROR
-                    e.to_string(),
-                    None,
-                ));
+                return Err(RpcError::internal(e));
             }
         }
 
-        self.store.remove_agreement(&uuid).await;
-        ResponsePayload::success(uuid.to_string())
+        let file_generator = FileGenerator::new(uuid, submit_request.proof_request_type);
+        let result = self
+            .jobs
+            .submit(
+                admission,
+                file_generator,
+                session_keys,
+                submit_request.encrypt_result,
+            )
+            .await;
+        if let Err(e) = result {
+            //the row was written, it must not stay pending forever
+            let failure = Failure::new(Some(FailureStage::Db), Some("db"), e.clone());
+            let _ = self.db.fail_proof(uuid, &failure).await;
+            return Err(RpcError::internal(e));
+        }
+
+        Ok(())
     }
 }
+
+//serves the methods on the listener behind the rate limiter, every request carries
+//the address of its client in its extensions so that the limiter can account it
+pub fn start(
+    listener: TcpListener,
+    methods: impl Into<Methods>,
+    rate_limiter: Arc<RateLimiter>,
+    trust_forwarded_for: bool,
+) -> ServerHandle {
+    let methods = methods.into();
+    let (stop_handle, server_handle) = stop_channel();
+    let service_builder = Server::builder()
+        .set_rpc_middleware(
+            RpcServiceBuilder::new()
+                .layer_fn(move |service| RateLimit::new(service, Arc::clone(&rate_limiter))),
+        )
+        .to_service_builder();
+
+    tokio::spawn(async move {
+        loop {
+            let (socket, peer) = tokio::select! {
+                result = listener.accept() => match result {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        dbg!(&e);
+                        continue;
+                    }
+                },
+                _ = stop_handle.clone().shutdown() => break,
+            };
+
+            let service = service_builder
+                .clone()
+                .build(methods.clone(), stop_handle.clone());
+            let service = tower::service_fn(move |mut request: HttpRequest<_>| {
+                let client_ip = ClientIp::of(&request, peer.ip(), trust_forwarded_for);
+                request.extensions_mut().insert(client_ip);
+                let mut service = service.clone();
+                async move { service.call(request).await }
+            });
+            tokio::spawn(serve_with_graceful_shutdown(
+                socket,
+                service,
+                stop_handle.clone().shutdown(),
+            ));
+        }
+    });
+
+    server_handle
+}
+
+//the admin methods reveal the load and the clients of the server, so they are kept
+//off the public listener and are not rate limited
+pub async fn start_admin(
+    address: &str,
+    methods: impl Into<Methods>,
+) -> Result<(ServerHandle, SocketAddr), std::io::Error> {
+    let server = Server::builder().build(address).await?;
+    let address = server.local_addr()?;
+    Ok((server.start(methods), address))
+}
@@ -1,45 +1,279 @@
 use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-// use std::collections::HashMap;
 use lru::LruCache;
 use tokio::sync::Mutex;
 
+use crate::key_schedule::SessionKeys;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionError {
+    NotFound,
+    //the session existed but outlived its ttl or was pushed out by newer sessions
+    Expired,
+    //the session was already used by a submit_request
+    Consumed,
+}
+
+struct Agreement {
+    keys: Arc<SessionKeys>,
+    created_at: Instant,
+}
+
 pub struct LruStore {
-    ecdh_store: Mutex<LruCache<String, Vec<u8>>>,
+    ecdh_store: Mutex<LruCache<uuid::Uuid, Agreement>>,
+    //sessions that are gone, remembered so that clients can be told why
+    tombstones: Mutex<LruCache<uuid::Uuid, SessionError>>,
+    ttl: Duration,
 }
 
 impl LruStore {
-    pub fn new(size: usize) -> Self {
+    pub fn new(size: usize, ttl: Duration) -> Self {
+        let size = NonZeroUsize::new(size).unwrap();
         Self {
-            ecdh_store: Mutex::new(LruCache::new(NonZeroUsize::new(size).unwrap())),
+            ecdh_store: Mutex::new(LruCache::new(size)),
+            tombstones: Mutex::new(LruCache::new(size)),
+            ttl,
         }
     }
+
+    fn is_expired(&self, agreement: &Agreement, now: Instant) -> bool {
+        now.saturating_duration_since(agreement.created_at) >= self.ttl
+    }
+
+    async fn mark_gone(&self, uuid: uuid::Uuid, reason: SessionError) {
+        self.tombstones.lock().await.put(uuid, reason);
+    }
 }
 
 impl LruStore {
     pub async fn insert_new_agreement(
         &self,
         uuid: uuid::Uuid,
-        shared_secret: Vec<u8>,
+        keys: SessionKeys,
     ) -> Result<(), String> {
-        let mut cache = self.ecdh_store.lock().await;
+        let now = Instant::now();
+        let evicted = {
+            let mut cache = self.ecdh_store.lock().await;
+
+            if let Some(agreement) = cache.peek(&uuid) {
+                if !self.is_expired(agreement, now) {
+                    return Err("Duplicate uuid".to_string());
+                }
+            }
+            cache.push(
+                uuid,
+                Agreement {
+                    keys: Arc::new(keys),
+                    created_at: now,
+                },
+            )
+        };
+
+        match evicted {
+            Some((evicted_uuid, _)) if evicted_uuid != uuid => {
+                self.mark_gone(evicted_uuid, SessionError::Expired).await
+            }
+            _ => {
+                self.tombstones.lock().await.pop(&uuid);
+            }
+        }
+
+        Ok(())
+    }
+
+    //the keys of a live session, the session is left in place so that a submission that
+    //fails to decrypt or is rejected does not use it up for the client
+    pub async fn session_keys(&self, uuid: &uuid::Uuid) -> Result<Arc<SessionKeys>, SessionError> {
+        let now = Instant::now();
+        let expired = {
+            let mut cache = self.ecdh_store.lock().await;
+            match cache.peek(uuid) {
+                Some(agreement) if !self.is_expired(agreement, now) => {
+                    return Ok(Arc::clone(&agreement.keys));
+                }
+                Some(_) => cache.pop(uuid).is_some(),
+                None => false,
+            }
+        };
+        match expired {
+            true => {
+                self.mark_gone(*uuid, SessionError::Expired).await;
+                Err(SessionError::Expired)
+            }
+            false => match self.tombstones.lock().await.get(uuid) {
+                Some(reason) => Err(*reason),
+                None => Err(SessionError::NotFound),
+            },
+        }
+    }
+
+    //a session serves a single submit_request, it is consumed once the request is
+    //accepted and a replayed submission is then told that it is a duplicate
+    pub async fn consume_session(&self, uuid: &uuid::Uuid) {
+        self.ecdh_store.lock().await.pop(uuid);
+        self.mark_gone(*uuid, SessionError::Consumed).await;
+    }
+
+    //drops every session that outlived the ttl, returns how many were dropped
+    pub async fn sweep(&self) -> usize {
+        let now = Instant::now();
+        let expired: Vec<uuid::Uuid> = {
+            let mut cache = self.ecdh_store.lock().await;
+            let expired: Vec<uuid::Uuid> = cache
+                .iter()
+                .filter(|(_, agreement)| self.is_expired(agreement, now))
+                .map(|(uuid, _)| *uuid)
+                .collect();
+            for uuid in &expired {
+                cache.pop(uuid);
+            }
+            expired
+        };
+
+        let count = expired.len();
+        for uuid in expired {
+            self.mark_gone(uuid, SessionError::Expired).await;
+        }
+        count
+    }
+
+    pub fn spawn_sweeper(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                self.sweep().await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zeroize::Zeroizing;
 
-        if cache.contains(&uuid.to_string()) {
-            return Err("Duplicate uuid".to_string());
-        } else {
-            cache.put(uuid.to_string(), shared_secret);
+    use super::*;
+    use crate::rpc_error::RpcError;
+    use crate::utils;
+
+    fn keys() -> SessionKeys {
+        SessionKeys {
+            request_key: Zeroizing::new([1; 32]),
+            response_key: Zeroizing::new([2; 32]),
         }
+    }
+
+    #[tokio::test]
+    async fn session_serves_a_single_submission() {
+        let store = LruStore::new(4, Duration::from_secs(60));
+        let uuid = uuid::Uuid::new_v4();
+        store.insert_new_agreement(uuid, keys()).await.unwrap();
 
-        return Ok(());
+        let session_keys = store.session_keys(&uuid).await.unwrap();
+        assert_eq!(*session_keys.request_key, [1; 32]);
+        store.consume_session(&uuid).await;
+
+        let replay = store.session_keys(&uuid).await.err().unwrap();
+        assert_eq!(replay, SessionError::Consumed);
+        assert!(matches!(
+            RpcError::session(uuid, replay),
+            RpcError::DuplicateRequest { uuid: duplicate } if duplicate == uuid
+        ));
     }
 
-    pub async fn get_shared_secret(&self, uuid: &uuid::Uuid) -> Option<Vec<u8>> {
-        let mut cache = self.ecdh_store.lock().await;
-        cache.get(&uuid.to_string()).map(|x| x.clone())
+    #[tokio::test]
+    async fn failed_decryption_leaves_the_session() {
+        let store = LruStore::new(4, Duration::from_secs(60));
+        let uuid = uuid::Uuid::new_v4();
+        store.insert_new_agreement(uuid, keys()).await.unwrap();
+
+        let session_keys = store.session_keys(&uuid).await.unwrap();
+        let forged = utils::decrypt(&session_keys.request_key, vec![0; 16], &[0; 16], &[0; 12]);
+        assert!(forged.is_err());
+
+        let session_keys = store.session_keys(&uuid).await.unwrap();
+        let sealed = utils::encrypt(&session_keys.request_key, b"request").unwrap();
+        let plaintext = utils::decrypt(
+            &session_keys.request_key,
+            sealed.cipher_text,
+            &sealed.auth_tag,
+            &sealed.nonce,
+        )
+        .unwrap();
+        assert_eq!(plaintext.as_str(), "request");
+    }
+
+    #[tokio::test]
+    async fn live_session_cannot_be_replaced() {
+        let store = LruStore::new(4, Duration::from_secs(60));
+        let uuid = uuid::Uuid::new_v4();
+        store.insert_new_agreement(uuid, keys()).await.unwrap();
+        assert!(store.insert_new_agreement(uuid, keys()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn session_expires_after_ttl() {
+        let ttl = Duration::from_millis(20);
+        let store = LruStore::new(4, ttl);
+        let uuid = uuid::Uuid::new_v4();
+        store.insert_new_agreement(uuid, keys()).await.unwrap();
+        tokio::time::sleep(ttl * 2).await;
+
+        assert_eq!(
+            store.session_keys(&uuid).await.err().unwrap(),
+            SessionError::Expired
+        );
+        //still expired rather than unknown once it was dropped
+        assert_eq!(
+            store.session_keys(&uuid).await.err().unwrap(),
+            SessionError::Expired
+        );
+        //an expired session can be started again
+        store.insert_new_agreement(uuid, keys()).await.unwrap();
+        assert!(store.session_keys(&uuid).await.is_ok());
     }
 
-    pub async fn remove_agreement(&self, uuid: &uuid::Uuid) {
-        let mut cache = self.ecdh_store.lock().await;
-        cache.pop(&uuid.to_string());
+    #[tokio::test]
+    async fn sweep_drops_expired_sessions() {
+        let ttl = Duration::from_millis(20);
+        let store = LruStore::new(4, ttl);
+        let expired = uuid::Uuid::new_v4();
+        store.insert_new_agreement(expired, keys()).await.unwrap();
+        tokio::time::sleep(ttl * 2).await;
+        let live = uuid::Uuid::new_v4();
+        store.insert_new_agreement(live, keys()).await.unwrap();
+
+        assert_eq!(store.sweep().await, 1);
+        assert_eq!(
+            store.session_keys(&expired).await.err().unwrap(),
+            SessionError::Expired
+        );
+        assert!(store.session_keys(&live).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn evicted_session_is_reported_expired() {
+        let store = LruStore::new(1, Duration::from_secs(60));
+        let evicted = uuid::Uuid::new_v4();
+        store.insert_new_agreement(evicted, keys()).await.unwrap();
+        store
+            .insert_new_agreement(uuid::Uuid::new_v4(), keys())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store.session_keys(&evicted).await.err().unwrap(),
+            SessionError::Expired
+        );
+        assert_eq!(
+            store
+                .session_keys(&uuid::Uuid::new_v4())
+                .await
+                .err()
+                .unwrap(),
+            SessionError::NotFound
+        );
     }
 }
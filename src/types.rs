@@ -1,23 +1,128 @@
+use std::collections::HashMap;
+
 use jsonrpsee::ResponsePayload;
 use serde::{Deserialize, Serialize};
 
 use crate::generator::Circuit;
+use crate::key_schedule::ProtocolVersion;
+use crate::retention::RetentionMode;
+use crate::scheduler::{Prioritized, PrioritySender};
 
 #[derive(Serialize, Clone)]
 pub struct HelloResponse {
     uuid: uuid::Uuid,
     attestation: Vec<u8>,
+    protocol_version: ProtocolVersion,
 }
 
 impl HelloResponse {
-    pub fn new(uuid: uuid::Uuid, attestation: Vec<u8>) -> Self {
-        HelloResponse { uuid, attestation }
+    pub fn new(uuid: uuid::Uuid, attestation: Vec<u8>, protocol_version: ProtocolVersion) -> Self {
+        HelloResponse {
+            uuid,
+            attestation,
+            protocol_version,
+        }
+    }
+}
+
+impl<'a> From<HelloResponse> for ResponsePayload<'a, HelloResponse> {
+    fn from(response: HelloResponse) -> ResponsePayload<'a, HelloResponse> {
+        ResponsePayload::success(response)
     }
 }
 
-impl<'a> Into<ResponsePayload<'a, HelloResponse>> for HelloResponse {
-    fn into(self) -> ResponsePayload<'a, HelloResponse> {
-        ResponsePayload::success(self)
+//the current HPKE key of openpassport_submit_sealed, with its attestation
+#[derive(Serialize, Clone)]
+pub struct HpkeKeyResponse {
+    pub key_id: u32,
+    pub public_key: Vec<u8>,
+    pub attestation: Vec<u8>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct QueueDepth {
+    pub len: usize,
+    pub capacity: usize,
+}
+
+impl QueueDepth {
+    pub fn of<T: Prioritized>(sender: &PrioritySender<T>) -> Self {
+        QueueDepth {
+            len: sender.len(),
+            capacity: sender.capacity(),
+        }
+    }
+}
+
+//accepted jobs that have not finished, and the jobs waiting in front of each pipeline stage
+#[derive(Serialize, Clone)]
+pub struct QueueDepths {
+    pub pending_jobs: QueueDepth,
+    pub file_generation: QueueDepth,
+    pub witness_generation: QueueDepth,
+    pub proof_generation: QueueDepth,
+}
+
+//rejections of the rate limiter since the server started
+#[derive(Serialize, Clone)]
+pub struct RateLimitStats {
+    pub tracked_clients: usize,
+    pub rejected_per_ip: u64,
+    pub rejected_per_method: HashMap<String, u64>,
+}
+
+//finished requests the retention task purged, by the status they ended in
+#[derive(Serialize, Clone, Default)]
+pub struct PurgeCounts {
+    pub proof_generated: u64,
+    pub failed: u64,
+    pub cancelled: u64,
+}
+
+//the retention policy and what it purged since the server started
+#[derive(Serialize, Clone)]
+pub struct RetentionReport {
+    pub enabled: bool,
+    pub retention_hours: Option<u64>,
+    pub mode: RetentionMode,
+    pub runs: u64,
+    pub last_run_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_error: Option<String>,
+    pub last_run: PurgeCounts,
+    pub total: PurgeCounts,
+}
+
+//AES-256-GCM output in the same shape submit_request takes its input
+#[derive(Serialize, Clone)]
+pub struct EncryptedPayload {
+    pub nonce: Vec<u8>,
+    pub cipher_text: Vec<u8>,
+    pub auth_tag: Vec<u8>,
+}
+
+impl EncryptedPayload {
+    //nonce || cipher text || auth tag
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            self.nonce.as_slice(),
+            self.cipher_text.as_slice(),
+            self.auth_tag.as_slice(),
+        ]
+        .concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 12 + 16 {
+            return None;
+        }
+        let (nonce, rest) = bytes.split_at(12);
+        let (cipher_text, auth_tag) = rest.split_at(rest.len() - 16);
+        Some(EncryptedPayload {
+            nonce: nonce.to_vec(),
+            cipher_text: cipher_text.to_vec(),
+            auth_tag: auth_tag.to_vec(),
+        })
     }
 }
 
@@ -25,6 +130,9 @@ impl<'a> Into<ResponsePayload<'a, HelloResponse>> for HelloResponse {
 #[serde(rename_all = "camelCase")]
 pub struct SubmitRequest {
     pub onchain: bool,
+    //seal the proof for the client instead of storing it in plaintext
+    #[serde(default)]
+    pub encrypt_result: bool,
     #[serde(flatten)]
     pub proof_request_type: ProofRequest,
 }
@@ -154,7 +262,8 @@ impl ProofRequest {
     }
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ProofType {
     Register,
     Dsc,
@@ -168,9 +277,9 @@ pub enum ProofType {
     DiscloseKyc,
 }
 
-impl Into<ProofType> for &ProofRequest {
-    fn into(self) -> ProofType {
-        match self {
+impl From<&ProofRequest> for ProofType {
+    fn from(proof_request: &ProofRequest) -> ProofType {
+        match proof_request {
             ProofRequest::Register { .. } => ProofType::Register,
             ProofRequest::Dsc { .. } => ProofType::Dsc,
             ProofRequest::Disclose { .. } => ProofType::Disclose,
@@ -202,9 +311,9 @@ impl std::fmt::Display for ProofType {
     }
 }
 
-impl Into<i32> for &ProofType {
-    fn into(self) -> i32 {
-        match self {
+impl From<&ProofType> for i32 {
+    fn from(proof_type: &ProofType) -> i32 {
+        match proof_type {
             ProofType::Register => 0,
             ProofType::Dsc => 1,
             ProofType::Disclose => 2,
@@ -238,9 +347,9 @@ impl TryFrom<i32> for ProofType {
     }
 }
 
-impl Into<i32> for ProofRequest {
-    fn into(self) -> i32 {
-        let proof_type: ProofType = (&self).into();
+impl From<ProofRequest> for i32 {
+    fn from(proof_request: ProofRequest) -> i32 {
+        let proof_type: ProofType = (&proof_request).into();
         (&proof_type).into()
     }
 }
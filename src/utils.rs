@@ -1,14 +1,18 @@
-use crate::db::fail_proof;
-use aes_gcm::aead::Aead;
-use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use crate::db::{Failure, ProofStore};
+use crate::types::EncryptedPayload;
+use aes_gcm::aead::{Aead, OsRng};
+use aes_gcm::{AeadCore, Aes256Gcm, Key, KeyInit, Nonce};
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+use zeroize::{Zeroize, Zeroizing};
 
 pub fn decrypt(
-    key: [u8; 32],
+    key: &[u8; 32],
     cipher_text: Vec<u8>,
     auth_tag: &[u8],
     nonce: &[u8],
-) -> Result<String, String> {
-    let key: &Key<Aes256Gcm> = (&key).into();
+) -> Result<Zeroizing<String>, String> {
+    let key: &Key<Aes256Gcm> = key.into();
 
     let cipher = Aes256Gcm::new(key);
 
@@ -22,59 +26,65 @@ pub fn decrypt(
         };
 
     match String::from_utf8(plaintext_bytes) {
-        Ok(plaintext) => Ok(plaintext),
-        Err(e) => Err(e.to_string()),
+        Ok(plaintext) => Ok(Zeroizing::new(plaintext)),
+        Err(e) => {
+            let message = e.to_string();
+            e.into_bytes().zeroize();
+            Err(message)
+        }
     }
 }
 
+pub fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<EncryptedPayload, String> {
+    let key: &Key<Aes256Gcm> = key.into();
+
+    let cipher = Aes256Gcm::new(key);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let mut cipher_text = match cipher.encrypt(&nonce, plaintext) {
+        Ok(cipher_text) => cipher_text,
+        Err(e) => return Err(e.to_string()),
+    };
+    let auth_tag = cipher_text.split_off(cipher_text.len() - 16);
+
+    Ok(EncryptedPayload {
+        nonce: nonce.to_vec(),
+        cipher_text,
+        auth_tag,
+    })
+}
+
 pub fn get_tmp_folder_path(uuid: &String) -> String {
     format!("./tmp_{}", uuid)
 }
 
-pub async fn cleanup(uuid: uuid::Uuid, pool: &sqlx::Pool<sqlx::Postgres>, reason: String) {
-    let tmp_folder = get_tmp_folder_path(&uuid.to_string());
-    let _ = fail_proof(uuid, &pool, reason).await;
-    let _ = tokio::fs::remove_dir_all(tmp_folder).await;
+pub async fn cleanup(uuid: uuid::Uuid, store: &dyn ProofStore, failure: Failure) {
+    let _ = store.fail_proof(uuid, &failure).await;
+    remove_tmp_folder(&uuid).await;
 }
 
-pub mod attestation {
-    use std::error::Error;
-
-    use hyper::body::Buf;
-    use hyper::{Body, Client, Request};
-    use hyperlocal::{UnixClientExt, Uri as HyperlocalUri};
-    use serde::Serialize;
-
-    #[derive(Serialize)]
-    struct TokenRequest<'a> {
-        audience: &'a str,
-        token_type: &'a str,
-        nonces: Vec<&'a str>,
+//the tmp folder holds the passport inputs and the witness, so its files are
+//overwritten before they are unlinked
+pub async fn remove_tmp_folder(uuid: &uuid::Uuid) {
+    let tmp_folder = get_tmp_folder_path(&uuid.to_string());
+    if let Ok(mut entries) = tokio::fs::read_dir(&tmp_folder).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if let Err(e) = wipe_file(&entry.path()).await {
+                dbg!(&e);
+            }
+        }
     }
+    let _ = tokio::fs::remove_dir_all(tmp_folder).await;
+}
 
-    pub async fn get_custom_token_bytes(nonces: Vec<&str>) -> Result<Vec<u8>, Box<dyn Error>> {
-        let request_body = TokenRequest {
-            audience: "USER",
-            token_type: "PKI",
-            nonces,
-        };
-        let json = serde_json::to_string(&request_body)?;
-
-        let client = Client::unix();
-        let socket_path = "/run/container_launcher/teeserver.sock";
-
-        // Fix: explicit type for URI
-        let uri: hyper::Uri = HyperlocalUri::new(socket_path, "/v1/token").into();
-
-        let req = Request::post(uri)
-            .header("Content-Type", "application/json")
-            .body(Body::from(json))?;
-
-        let res = client.request(req).await?;
-        let mut bytes = hyper::body::aggregate(res).await?;
-        let token_bytes = bytes.copy_to_bytes(bytes.remaining()).to_vec();
-
-        println!("Token Response: {}", String::from_utf8_lossy(&token_bytes));
-        Ok(token_bytes)
+async fn wipe_file(path: &Path) -> std::io::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+    let zeros = vec![0u8; 64 * 1024];
+    let mut remaining = file.metadata().await?.len();
+    while remaining > 0 {
+        let len = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..len]).await?;
+        remaining -= len as u64;
     }
+    file.sync_all().await
 }
@@ -0,0 +1,113 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// the witness calculator keeps the circuit's .dat constants in memory next to the
+// signal buffers, rapidsnark loads the whole zkey plus the witness and its FFT buffers;
+// both end up at roughly twice the size of the file they read
+const MEMORY_ESTIMATE_FACTOR: u64 = 2;
+const MIN_MEMORY_ESTIMATE_MB: u32 = 64;
+
+// share of the total memory that jobs may claim, the rest is left to the server itself
+const DEFAULT_MEMORY_BUDGET_PERCENT: u64 = 80;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitCost {
+    pub witness_mb: u32,
+    pub prover_mb: u32,
+}
+
+impl CircuitCost {
+    pub fn estimate(circuit_folder: &Path, circuit_name: &str, zkey_path: &Path) -> Self {
+        let dat_path = circuit_folder
+            .join(format!("{}_cpp", circuit_name))
+            .join(format!("{}.dat", circuit_name));
+        Self {
+            witness_mb: estimate_memory_mb(&dat_path),
+            prover_mb: estimate_memory_mb(zkey_path),
+        }
+    }
+}
+
+fn estimate_memory_mb(path: &Path) -> u32 {
+    let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    let estimate_mb = size * MEMORY_ESTIMATE_FACTOR / (1024 * 1024);
+    (estimate_mb.min(u32::MAX as u64) as u32).max(MIN_MEMORY_ESTIMATE_MB)
+}
+
+//80% of MemTotal, None if /proc/meminfo cannot be read
+pub fn default_memory_budget_mb() -> Option<u32> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let total_kb: u64 = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemTotal:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()?;
+    Some((total_kb / 1024 * DEFAULT_MEMORY_BUDGET_PERCENT / 100).min(u32::MAX as u64) as u32)
+}
+
+//memory shared by every stage, one permit per MB
+#[derive(Clone)]
+pub struct MemoryBudget {
+    total_mb: u32,
+    permits: Arc<Semaphore>,
+}
+
+impl MemoryBudget {
+    pub fn new(total_mb: u32) -> Self {
+        Self {
+            total_mb,
+            permits: Arc::new(Semaphore::new(total_mb as usize)),
+        }
+    }
+}
+
+pub struct WorkerPermit {
+    _worker: OwnedSemaphorePermit,
+    memory: Option<OwnedSemaphorePermit>,
+}
+
+//bounds the number of jobs a stage runs at once and, with a budget, their memory
+pub struct WorkerPool {
+    workers: Arc<Semaphore>,
+    memory: Option<MemoryBudget>,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize, memory: Option<MemoryBudget>) -> Self {
+        Self {
+            workers: Arc::new(Semaphore::new(workers)),
+            memory,
+        }
+    }
+
+    //waits for a free worker, the job is only picked once one is available
+    pub async fn acquire_worker(&self) -> WorkerPermit {
+        let worker = Arc::clone(&self.workers)
+            .acquire_owned()
+            .await
+            .expect("worker semaphore is never closed");
+
+        WorkerPermit {
+            _worker: worker,
+            memory: None,
+        }
+    }
+
+    //waits until the memory the picked job needs is available
+    pub async fn reserve_memory(&self, permit: &mut WorkerPermit, memory_mb: u32) {
+        if let Some(budget) = &self.memory {
+            //a job larger than the whole budget runs alone instead of waiting forever
+            let memory_mb = memory_mb.min(budget.total_mb);
+            permit.memory = Some(
+                Arc::clone(&budget.permits)
+                    .acquire_many_owned(memory_mb)
+                    .await
+                    .expect("memory semaphore is never closed"),
+            );
+        }
+    }
+}